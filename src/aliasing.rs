@@ -0,0 +1,72 @@
+use crate::syntax::*;
+
+// works out whether an assignment reads the variable it writes to, which decides if eigen can
+// write the result directly into it (`noalias()`) and if it can be turned into `+=`, `-=` or `*=`
+
+// the variable (or struct field) an lvalue writes to - struct fields are only aliased by the same
+// field, so they are tracked as `prefix.name`
+pub fn written_variable(lvalue: &MLtLValue) -> Option<String> {
+    let matrix_name = |matrix: &MLtMatrixAccess| match matrix {
+        MLtMatrixAccess::Matrix(name)
+        | MLtMatrixAccess::MatrixIndex(name, _)
+        | MLtMatrixAccess::MatrixSegment(name, _)
+        | MLtMatrixAccess::MatrixMultiSegment(name, _)
+        | MLtMatrixAccess::MatrixBlock(name, _, _) => name.clone(),
+    };
+    match lvalue {
+        MLtLValue::Matrix(matrix) => Some(matrix_name(matrix)),
+        MLtLValue::StructMatrix(prefix, matrix) => {
+            Some(format!("{}.{}", prefix, matrix_name(matrix)))
+        }
+        _ => None,
+    }
+}
+
+pub fn reads_variable(expr: &MLtExpr, name: &str) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => {
+            rows.iter().flatten().any(|e| reads_variable(e, name))
+        }
+        MLtExpr::Basic(MLtLValue::FunctionCall(_, exprs)) => {
+            exprs.iter().any(|e| reads_variable(e, name))
+        }
+        MLtExpr::Basic(lvalue) => written_variable(lvalue).is_some_and(|n| n == name),
+        MLtExpr::Negation(e)
+        | MLtExpr::Transposed(e)
+        | MLtExpr::PlainTransposed(e)
+        | MLtExpr::Parenthesized(e)
+        | MLtExpr::Normalized(e)
+        | MLtExpr::SquaredNorm(e)
+        | MLtExpr::Skew(e) => reads_variable(e, name),
+        MLtExpr::BinOp(left, _, right) | MLtExpr::SymmetricProduct(left, right) => {
+            reads_variable(left, name) || reads_variable(right, name)
+        }
+    }
+}
+
+// `x = x + e`, `x = e + x`, `x = x - e` and `x = x * e` - returns the operator and `e`
+pub fn compound_update<'a>(
+    lvalue: &MLtLValue,
+    expr: &'a MLtExpr,
+) -> Option<(&'a MLtBinOp, &'a MLtExpr)> {
+    let MLtExpr::BinOp(left, op, right) = expr else {
+        return None;
+    };
+    let is_target = |e: &MLtExpr| matches!(e, MLtExpr::Basic(l) if l == lvalue);
+    match op {
+        MLtBinOp::Add | MLtBinOp::Sub | MLtBinOp::Mul if is_target(left) => Some((op, right)),
+        // addition commutes, matrix multiplication doesn't
+        MLtBinOp::Add if is_target(right) => Some((op, left)),
+        _ => None,
+    }
+}
+
+// the right side when it is a product, which eigen evaluates into a temporary unless told the
+// destination isn't aliased - `noalias()` is only defined for assigning a product, not a sum of them
+pub fn top_level_product(expr: &MLtExpr) -> Option<&MLtExpr> {
+    match expr {
+        MLtExpr::Parenthesized(e) => top_level_product(e),
+        MLtExpr::BinOp(_, MLtBinOp::Mul, _) | MLtExpr::SymmetricProduct(_, _) => Some(expr),
+        _ => None,
+    }
+}
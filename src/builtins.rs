@@ -0,0 +1,139 @@
+// table of elementwise math builtins, which map to std:: calls on scalars and eigen array ops on
+// matrices
+
+// scalar form: a function called with the operands, or a template where `{0}` is the operand
+// matrix form: a template where `{0}` is the operand
+const UNARY_ELEMENTWISE: [(&str, &str, &str); 19] = [
+    ("abs", "std::abs", "{0}.cwiseAbs()"),
+    ("exp", "std::exp", "{0}.array().exp().matrix()"),
+    ("sqrt", "std::sqrt", "{0}.array().sqrt().matrix()"),
+    ("sin", "std::sin", "{0}.array().sin().matrix()"),
+    ("cos", "std::cos", "{0}.array().cos().matrix()"),
+    ("tan", "std::tan", "{0}.array().tan().matrix()"),
+    ("asin", "std::asin", "{0}.array().asin().matrix()"),
+    ("acos", "std::acos", "{0}.array().acos().matrix()"),
+    ("atan", "std::atan", "{0}.array().atan().matrix()"),
+    ("log", "std::log", "{0}.array().log().matrix()"),
+    ("log10", "std::log10", "{0}.array().log10().matrix()"),
+    (
+        "sign",
+        "(({0} > 0) - ({0} < 0))",
+        "{0}.array().sign().matrix()",
+    ),
+    ("floor", "std::floor", "{0}.array().floor().matrix()"),
+    ("ceil", "std::ceil", "{0}.array().ceil().matrix()"),
+    ("round", "std::round", "{0}.array().round().matrix()"),
+    ("conj", "std::conj", "{0}.conjugate()"),
+    ("real", "std::real", "{0}.real()"),
+    ("imag", "std::imag", "{0}.imag()"),
+    ("angle", "std::arg", "{0}.array().arg().matrix()"),
+];
+
+// scalar form: same as above with `{0}` and `{1}` as the operands
+// matrix form: the body of a lambda over the coefficients `a` and `b`, passed to binaryExpr
+const BINARY_ELEMENTWISE: [(&str, &str, &str); 4] = [
+    ("atan2", "std::atan2", "std::atan2(a, b)"),
+    // matlab's mod(a, 0) is a
+    (
+        "mod",
+        "({1} == 0 ? {0} : {0} - std::floor({0} / {1}) * {1})",
+        "b == 0 ? a : a - std::floor(a / b) * b",
+    ),
+    ("rem", "std::fmod", "std::fmod(a, b)"),
+    ("hypot", "std::hypot", "std::hypot(a, b)"),
+];
+
+// builtins with their own handling in type_inference and eigen_output, `selfadjoint_eig` is eig
+// of a symmetric matrix, renamed in the transform pass - rand and randn
+// aren't here, every call gives a different result
+const OTHER_BUILTINS: [&str; 32] = [
+    "eye",
+    "zeros",
+    "ones",
+    "expm",
+    "diag",
+    "norm",
+    "isempty",
+    "min",
+    "max",
+    "cross",
+    "sum",
+    "prod",
+    "mean",
+    "any",
+    "all",
+    "trace",
+    "det",
+    "inv",
+    "pinv",
+    "chol",
+    "eig",
+    "selfadjoint_eig",
+    "svd",
+    "qr",
+    "kron",
+    "repmat",
+    "linspace",
+    "reshape",
+    "blkdiag",
+    "size",
+    "numel",
+    "length",
+];
+
+// scalar first quaternion functions from the aerospace toolbox, only converted to
+// `Eigen::Quaternion` operations with the `quaternions` option
+const QUATERNION_BUILTINS: [&str; 6] = [
+    "quatmultiply",
+    "quatconj",
+    "quatinv",
+    "quatnormalize",
+    "quat2rotm",
+    "rotm2quat",
+];
+
+// returns (scalar form, matrix form)
+pub fn unary_elementwise(function_name: &str) -> Option<(&'static str, &'static str)> {
+    UNARY_ELEMENTWISE
+        .iter()
+        .find(|(name, _, _)| *name == function_name)
+        .map(|(_, scalar, matrix)| (*scalar, *matrix))
+}
+
+// returns (scalar form, lambda body)
+pub fn binary_elementwise(function_name: &str) -> Option<(&'static str, &'static str)> {
+    BINARY_ELEMENTWISE
+        .iter()
+        .find(|(name, _, _)| *name == function_name)
+        .map(|(_, scalar, lambda)| (*scalar, *lambda))
+}
+
+pub fn is_elementwise(function_name: &str) -> bool {
+    unary_elementwise(function_name).is_some() || binary_elementwise(function_name).is_some()
+}
+
+// fills in a scalar form - plain function names are called with the raw operands, templates get
+// the parenthesized operands so the substitution can't change precedence
+pub fn scalar_form_to_cpp(form: &str, raw_operands: &[String], operands: &[String]) -> String {
+    if form.contains('{') {
+        operands
+            .iter()
+            .enumerate()
+            .fold(form.to_string(), |form, (i, operand)| {
+                form.replace(&format!("{{{}}}", i), operand)
+            })
+    } else {
+        format!("{}({})", form, raw_operands.join(", "))
+    }
+}
+
+pub fn is_quaternion(function_name: &str) -> bool {
+    QUATERNION_BUILTINS.contains(&function_name)
+}
+
+// builtins have no side effects, so calls to them can be reordered or deduplicated
+pub fn is_builtin(function_name: &str) -> bool {
+    is_elementwise(function_name)
+        || is_quaternion(function_name)
+        || OTHER_BUILTINS.contains(&function_name)
+}
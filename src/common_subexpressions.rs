@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use crate::builtins;
+use crate::syntax::*;
+
+// hoists products that are computed more than once within a basic block (a statement list without
+// the bodies of its if statements) into temporaries, so `H * P * H'` is only evaluated once
+// the type of the temporary is inferred when the output is generated
+
+// only products are worth hoisting, and only if they can't have side effects
+fn is_candidate(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::BinOp(left, MLtBinOp::Mul, right) => {
+            !is_number(left) && !is_number(right) && is_pure(expr)
+        }
+        _ => false,
+    }
+}
+
+fn is_number(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(_) | MLtLValue::Float(_)) => true,
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e) => is_number(e),
+        _ => false,
+    }
+}
+
+fn is_pure(expr: &MLtExpr) -> bool {
+    let mut pure = true;
+    visit_subexpressions(expr, &mut |e| {
+        if let MLtExpr::Basic(MLtLValue::FunctionCall(name, _)) = e
+            && !builtins::is_builtin(name)
+        {
+            pure = false;
+        }
+    });
+    pure
+}
+
+fn expr_size(expr: &MLtExpr) -> usize {
+    let mut size = 0;
+    visit_subexpressions(expr, &mut |_| size += 1);
+    size
+}
+
+// calls `f` on the expression and every expression inside it
+pub fn visit_subexpressions(expr: &MLtExpr, f: &mut impl FnMut(&MLtExpr)) {
+    f(expr);
+    match expr {
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => rows
+            .iter()
+            .flatten()
+            .for_each(|e| visit_subexpressions(e, f)),
+        MLtExpr::Basic(MLtLValue::FunctionCall(_, exprs)) => {
+            exprs.iter().for_each(|e| visit_subexpressions(e, f))
+        }
+        MLtExpr::Basic(_) => {}
+        MLtExpr::Negation(e)
+        | MLtExpr::Transposed(e)
+        | MLtExpr::PlainTransposed(e)
+        | MLtExpr::Parenthesized(e)
+        | MLtExpr::Normalized(e)
+        | MLtExpr::SquaredNorm(e)
+        | MLtExpr::Skew(e) => visit_subexpressions(e, f),
+        MLtExpr::BinOp(left, _, right) | MLtExpr::SymmetricProduct(left, right) => {
+            visit_subexpressions(left, f);
+            visit_subexpressions(right, f);
+        }
+    }
+}
+
+fn replace_subexpression(expr: MLtExpr, target: &MLtExpr, replacement: &MLtExpr) -> MLtExpr {
+    if &expr == target {
+        return replacement.clone();
+    }
+    let replace = |e: MLtExpr| replace_subexpression(e, target, replacement);
+    let replace_boxed = |e: Box<MLtExpr>| Box::new(replace_subexpression(*e, target, replacement));
+    match expr {
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => MLtExpr::Basic(MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(replace).collect())
+                .collect(),
+        )),
+        MLtExpr::Basic(MLtLValue::FunctionCall(name, exprs)) => MLtExpr::Basic(
+            MLtLValue::FunctionCall(name, exprs.into_iter().map(replace).collect()),
+        ),
+        MLtExpr::Basic(_) => expr,
+        MLtExpr::Negation(e) => MLtExpr::Negation(replace_boxed(e)),
+        MLtExpr::Transposed(e) => MLtExpr::Transposed(replace_boxed(e)),
+        MLtExpr::PlainTransposed(e) => MLtExpr::PlainTransposed(replace_boxed(e)),
+        MLtExpr::Parenthesized(e) => MLtExpr::Parenthesized(replace_boxed(e)),
+        MLtExpr::Normalized(e) => MLtExpr::Normalized(replace_boxed(e)),
+        MLtExpr::SquaredNorm(e) => MLtExpr::SquaredNorm(replace_boxed(e)),
+        MLtExpr::Skew(e) => MLtExpr::Skew(replace_boxed(e)),
+        MLtExpr::BinOp(left, op, right) => {
+            MLtExpr::BinOp(replace_boxed(left), op, replace_boxed(right))
+        }
+        MLtExpr::SymmetricProduct(left, middle) => {
+            MLtExpr::SymmetricProduct(replace_boxed(left), replace_boxed(middle))
+        }
+    }
+}
+
+fn variable_name(lvalue: &MLtLValue) -> Option<String> {
+    let matrix_name = |matrix: &MLtMatrixAccess| match matrix {
+        MLtMatrixAccess::Matrix(name)
+        | MLtMatrixAccess::MatrixIndex(name, _)
+        | MLtMatrixAccess::MatrixSegment(name, _)
+        | MLtMatrixAccess::MatrixMultiSegment(name, _)
+        | MLtMatrixAccess::MatrixBlock(name, _, _) => name.clone(),
+    };
+    match lvalue {
+        MLtLValue::Matrix(matrix) => Some(matrix_name(matrix)),
+        // writing to any field of a struct invalidates reads of the whole struct
+        MLtLValue::StructMatrix(prefix, _) => Some(prefix.clone()),
+        _ => None,
+    }
+}
+
+fn read_variables(expr: &MLtExpr) -> Vec<String> {
+    let mut names = vec![];
+    visit_subexpressions(expr, &mut |e| {
+        if let MLtExpr::Basic(lvalue) = e
+            && let Some(name) = variable_name(lvalue)
+            && !names.contains(&name)
+        {
+            names.push(name);
+        }
+    });
+    names
+}
+
+// every variable a statement (or the body of an if statement) writes to
+fn assigned_variables(statement: &MLtStatement, names: &mut Vec<String>) {
+    match statement {
+        MLtStatement::Assignment(lvalue, _) => names.extend(variable_name(lvalue)),
+        MLtStatement::MultiAssignment(lvalues, _) => {
+            names.extend(lvalues.iter().filter_map(variable_name))
+        }
+        MLtStatement::Normalization(lvalue) => names.extend(variable_name(lvalue)),
+        MLtStatement::Symmetrize(name)
+        | MLtStatement::Constant(name, _)
+        | MLtStatement::Temporary(name, _) => names.push(name.clone()),
+        MLtStatement::IfStatement(_, body) | MLtStatement::PersistentInit(_, body) => {
+            body.iter().for_each(|s| assigned_variables(s, names))
+        }
+        _ => {}
+    }
+}
+
+// the expressions evaluated by the statement itself (not by the body of an if)
+pub fn statement_expressions(statement: &MLtStatement) -> Vec<&MLtExpr> {
+    match statement {
+        MLtStatement::Assignment(_, expr)
+        | MLtStatement::MultiAssignment(_, expr)
+        | MLtStatement::IfStatement(expr, _)
+        | MLtStatement::Constant(_, expr)
+        | MLtStatement::Temporary(_, expr) => vec![expr],
+        _ => vec![],
+    }
+}
+
+fn replace_in_statement(
+    statement: MLtStatement,
+    target: &MLtExpr,
+    replacement: &MLtExpr,
+) -> MLtStatement {
+    match statement {
+        MLtStatement::Assignment(lvalue, expr) => {
+            MLtStatement::Assignment(lvalue, replace_subexpression(expr, target, replacement))
+        }
+        MLtStatement::MultiAssignment(lvalues, expr) => {
+            MLtStatement::MultiAssignment(lvalues, replace_subexpression(expr, target, replacement))
+        }
+        MLtStatement::IfStatement(expr, body) => {
+            MLtStatement::IfStatement(replace_subexpression(expr, target, replacement), body)
+        }
+        MLtStatement::Constant(name, expr) => {
+            MLtStatement::Constant(name, replace_subexpression(expr, target, replacement))
+        }
+        MLtStatement::Temporary(name, expr) => {
+            MLtStatement::Temporary(name, replace_subexpression(expr, target, replacement))
+        }
+        statement => statement,
+    }
+}
+
+// occurrences of the same expression only match if none of the variables it reads were written
+// to in between, so each occurrence is keyed by the number of writes to each variable it reads
+struct Occurrences {
+    expr: MLtExpr,
+    generations: Vec<u32>,
+    statement_idxs: Vec<usize>,
+    count: usize,
+}
+
+// finds the largest expression that is computed more than once
+fn find_repeated(statements: &[MLtStatement]) -> Option<Occurrences> {
+    let mut generations: HashMap<String, u32> = HashMap::new();
+    let mut occurrences: Vec<Occurrences> = vec![];
+
+    for (idx, statement) in statements.iter().enumerate() {
+        for expr in statement_expressions(statement) {
+            visit_subexpressions(expr, &mut |e| {
+                if !is_candidate(e) {
+                    return;
+                }
+                let key: Vec<u32> = read_variables(e)
+                    .iter()
+                    .map(|name| generations.get(name).copied().unwrap_or(0))
+                    .collect();
+                match occurrences
+                    .iter_mut()
+                    .find(|o| &o.expr == e && o.generations == key)
+                {
+                    Some(o) => {
+                        o.count += 1;
+                        if !o.statement_idxs.contains(&idx) {
+                            o.statement_idxs.push(idx);
+                        }
+                    }
+                    None => occurrences.push(Occurrences {
+                        expr: e.clone(),
+                        generations: key,
+                        statement_idxs: vec![idx],
+                        count: 1,
+                    }),
+                }
+            });
+        }
+
+        let mut assigned = vec![];
+        assigned_variables(statement, &mut assigned);
+        for name in assigned {
+            *generations.entry(name).or_default() += 1;
+        }
+    }
+
+    occurrences
+        .into_iter()
+        .filter(|o| o.count > 1)
+        .fold(None, |best: Option<Occurrences>, o| match best {
+            Some(best) if expr_size(&best.expr) >= expr_size(&o.expr) => Some(best),
+            _ => Some(o),
+        })
+}
+
+fn eliminate_in_block(
+    mut statements: Vec<MLtStatement>,
+    temp_count: &mut u32,
+) -> Vec<MLtStatement> {
+    while let Some(repeated) = find_repeated(&statements) {
+        let temp_name = format!("cse_{}", temp_count);
+        *temp_count += 1;
+        let temp = MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(
+            temp_name.clone(),
+        )));
+
+        statements = statements
+            .into_iter()
+            .enumerate()
+            .map(|(idx, s)| {
+                if repeated.statement_idxs.contains(&idx) {
+                    replace_in_statement(s, &repeated.expr, &temp)
+                } else {
+                    s
+                }
+            })
+            .collect();
+        statements.insert(
+            repeated.statement_idxs[0],
+            MLtStatement::Temporary(temp_name, repeated.expr),
+        );
+    }
+
+    statements
+        .into_iter()
+        .map(|s| match s {
+            MLtStatement::IfStatement(cond, body) => {
+                MLtStatement::IfStatement(cond, eliminate_in_block(body, temp_count))
+            }
+            MLtStatement::PersistentInit(name, body) => {
+                MLtStatement::PersistentInit(name, eliminate_in_block(body, temp_count))
+            }
+            s => s,
+        })
+        .collect()
+}
+
+pub fn eliminate_common_subexpressions(mut function: MLtFunction) -> MLtFunction {
+    let mut temp_count = 0;
+    function.body = eliminate_in_block(function.body, &mut temp_count);
+    function
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use crate::options::Options;
+use crate::syntax::*;
+
+// propagates scalar constants (variables assigned a literal exactly once, outside of any if), folds
+// the arithmetic and comparisons they take part in, and removes if statements that become
+// provably false (or unwraps provably true ones)
+
+fn literal(value: f64) -> MLtExpr {
+    if value < 0.0 {
+        return MLtExpr::Negation(Box::new(literal(-value)));
+    }
+    if value.fract() == 0.0 && value < 1e15 {
+        MLtExpr::Basic(MLtLValue::Integer(format!("{}", value as i64)))
+    } else {
+        MLtExpr::Basic(MLtLValue::Float(format!("{}", value)))
+    }
+}
+
+// NaN and inf have no C++ literal, so expressions that give them aren't folded
+fn finite(value: f64) -> Option<f64> {
+    Some(value).filter(|value| value.is_finite())
+}
+
+// `a && b` is 0 or 1 in matlab, so `1 && x` is `x != 0` unless x is already a logical value
+fn logical(expr: MLtExpr) -> MLtExpr {
+    match expr {
+        MLtExpr::BinOp(
+            _,
+            MLtBinOp::And
+            | MLtBinOp::Or
+            | MLtBinOp::EqualTo
+            | MLtBinOp::NotEqualTo
+            | MLtBinOp::LessThan
+            | MLtBinOp::LessThanEqualTo
+            | MLtBinOp::GreaterThan
+            | MLtBinOp::GreaterThanEqualTo,
+            _,
+        ) => expr,
+        MLtExpr::Parenthesized(inner) => match logical(*inner) {
+            inner @ MLtExpr::Parenthesized(_) => inner,
+            inner => MLtExpr::Parenthesized(Box::new(inner)),
+        },
+        expr => MLtExpr::Parenthesized(Box::new(MLtExpr::BinOp(
+            Box::new(expr),
+            MLtBinOp::NotEqualTo,
+            Box::new(literal(0.0)),
+        ))),
+    }
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+fn fold_binop(left: f64, op: &MLtBinOp, right: f64) -> Option<f64> {
+    let value = match op {
+        MLtBinOp::Add => Some(left + right),
+        MLtBinOp::Sub => Some(left - right),
+        MLtBinOp::Mul | MLtBinOp::CwiseMul => Some(left * right),
+        // leave division by zero for the C++ compiler to deal with
+        MLtBinOp::Div | MLtBinOp::CwiseDiv if right == 0.0 => None,
+        MLtBinOp::Div | MLtBinOp::CwiseDiv => Some(left / right),
+        MLtBinOp::Pow | MLtBinOp::CwisePow => Some(left.powf(right)),
+        MLtBinOp::And => Some(bool_value(left != 0.0 && right != 0.0)),
+        MLtBinOp::Or => Some(bool_value(left != 0.0 || right != 0.0)),
+        MLtBinOp::EqualTo => Some(bool_value(left == right)),
+        MLtBinOp::NotEqualTo => Some(bool_value(left != right)),
+        MLtBinOp::LessThan => Some(bool_value(left < right)),
+        MLtBinOp::LessThanEqualTo => Some(bool_value(left <= right)),
+        MLtBinOp::GreaterThan => Some(bool_value(left > right)),
+        MLtBinOp::GreaterThanEqualTo => Some(bool_value(left >= right)),
+    };
+    value.and_then(finite)
+}
+
+// returns the folded expression and its value if it is a compile time constant
+// `keep_names` leaves references to constants in place (for constexpr), but still folds any
+// expression they are part of
+fn fold_expression(
+    expr: MLtExpr,
+    constants: &HashMap<String, f64>,
+    keep_names: bool,
+) -> (MLtExpr, Option<f64>) {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(ref v)) | MLtExpr::Basic(MLtLValue::Float(ref v)) => {
+            let value = v.parse().ok().and_then(finite);
+            (expr, value)
+        }
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(ref name))) => {
+            match constants.get(name) {
+                Some(value) if keep_names => (expr, Some(*value)),
+                Some(value) => (literal(*value), Some(*value)),
+                None => (expr, None),
+            }
+        }
+        MLtExpr::Basic(lvalue) => (
+            MLtExpr::Basic(fold_lvalue(lvalue, constants, keep_names)),
+            None,
+        ),
+        MLtExpr::Negation(mlt_expr) => match fold_expression(*mlt_expr, constants, keep_names) {
+            (_, Some(value)) => (literal(-value), Some(-value)),
+            (mlt_expr, None) => (MLtExpr::Negation(Box::new(mlt_expr)), None),
+        },
+        // transposing a scalar doesn't change it
+        MLtExpr::Transposed(mlt_expr) => match fold_expression(*mlt_expr, constants, keep_names) {
+            (_, Some(value)) => (literal(value), Some(value)),
+            (mlt_expr, None) => (MLtExpr::Transposed(Box::new(mlt_expr)), None),
+        },
+        MLtExpr::PlainTransposed(mlt_expr) => {
+            match fold_expression(*mlt_expr, constants, keep_names) {
+                (_, Some(value)) => (literal(value), Some(value)),
+                (mlt_expr, None) => (MLtExpr::PlainTransposed(Box::new(mlt_expr)), None),
+            }
+        }
+        MLtExpr::Parenthesized(mlt_expr) => {
+            match fold_expression(*mlt_expr, constants, keep_names) {
+                (_, Some(value)) => (literal(value), Some(value)),
+                (mlt_expr, None) => (MLtExpr::Parenthesized(Box::new(mlt_expr)), None),
+            }
+        }
+        MLtExpr::BinOp(left, op, right) => {
+            let (left, left_value) = fold_expression(*left, constants, keep_names);
+            let (right, right_value) = fold_expression(*right, constants, keep_names);
+            if let (Some(l), Some(r)) = (left_value, right_value)
+                && let Some(value) = fold_binop(l, &op, r)
+            {
+                return (literal(value), Some(value));
+            }
+            match (left_value, &op, right_value) {
+                // short circuit logic with one constant side
+                (Some(l), MLtBinOp::Or, _) | (_, MLtBinOp::Or, Some(l)) if l != 0.0 => {
+                    (literal(1.0), Some(1.0))
+                }
+                (Some(l), MLtBinOp::And, _) | (_, MLtBinOp::And, Some(l)) if l == 0.0 => {
+                    (literal(0.0), Some(0.0))
+                }
+                (Some(_), MLtBinOp::And | MLtBinOp::Or, _) => (logical(right), None),
+                (_, MLtBinOp::And | MLtBinOp::Or, Some(_)) => (logical(left), None),
+                // multiplying by one (usually a folded condition) is a no-op
+                (Some(1.0), MLtBinOp::Mul | MLtBinOp::CwiseMul, _) => (right, None),
+                (_, MLtBinOp::Mul | MLtBinOp::CwiseMul | MLtBinOp::Div, Some(1.0)) => (left, None),
+                _ => (MLtExpr::BinOp(Box::new(left), op, Box::new(right)), None),
+            }
+        }
+        // normalizing a scalar gives its sign, which is rare enough to leave to the C++ compiler
+        MLtExpr::Normalized(mlt_expr) => (
+            MLtExpr::Normalized(Box::new(
+                fold_expression(*mlt_expr, constants, keep_names).0,
+            )),
+            None,
+        ),
+        MLtExpr::SquaredNorm(mlt_expr) => match fold_expression(*mlt_expr, constants, keep_names) {
+            (_, Some(value)) => (literal(value * value), Some(value * value)),
+            (mlt_expr, None) => (MLtExpr::SquaredNorm(Box::new(mlt_expr)), None),
+        },
+        MLtExpr::Skew(mlt_expr) => (
+            MLtExpr::Skew(Box::new(
+                fold_expression(*mlt_expr, constants, keep_names).0,
+            )),
+            None,
+        ),
+        MLtExpr::SymmetricProduct(left, middle) => (
+            MLtExpr::SymmetricProduct(
+                Box::new(fold_expression(*left, constants, keep_names).0),
+                Box::new(fold_expression(*middle, constants, keep_names).0),
+            ),
+            None,
+        ),
+    }
+}
+
+fn fold_lvalue(lvalue: MLtLValue, constants: &HashMap<String, f64>, keep_names: bool) -> MLtLValue {
+    match lvalue {
+        MLtLValue::InlineMatrix(rows) => MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|e| fold_expression(e, constants, keep_names).0)
+                        .collect()
+                })
+                .collect(),
+        ),
+        // matrix sizes have to be literals for the type to be known
+        MLtLValue::FunctionCall(name, mlt_exprs) => {
+            let keep_names = keep_names
+                && ![
+                    "eye", "zeros", "ones", "repmat", "linspace", "reshape", "rand", "randn",
+                ]
+                .contains(&name.as_str());
+            MLtLValue::FunctionCall(
+                name,
+                mlt_exprs
+                    .into_iter()
+                    .map(|e| fold_expression(e, constants, keep_names).0)
+                    .collect(),
+            )
+        }
+        _ => lvalue,
+    }
+}
+
+fn assigned_name(lvalue: &MLtLValue) -> Option<&String> {
+    match lvalue {
+        MLtLValue::Matrix(
+            MLtMatrixAccess::Matrix(name)
+            | MLtMatrixAccess::MatrixIndex(name, _)
+            | MLtMatrixAccess::MatrixSegment(name, _)
+            | MLtMatrixAccess::MatrixMultiSegment(name, _)
+            | MLtMatrixAccess::MatrixBlock(name, _, _),
+        ) => Some(name),
+        _ => None,
+    }
+}
+
+// counts every (full or partial) assignment to each variable, including inside if statements
+fn count_assignments(statements: &[MLtStatement], counts: &mut HashMap<String, u32>) {
+    for statement in statements {
+        match statement {
+            MLtStatement::Assignment(lvalue, _) => {
+                if let Some(name) = assigned_name(lvalue) {
+                    *counts.entry(name.clone()).or_default() += 1;
+                }
+            }
+            MLtStatement::MultiAssignment(lvalues, _) => {
+                for name in lvalues.iter().filter_map(assigned_name) {
+                    *counts.entry(name.clone()).or_default() += 1;
+                }
+            }
+            MLtStatement::Normalization(lvalue) => {
+                if let Some(name) = assigned_name(lvalue) {
+                    *counts.entry(name.clone()).or_default() += 1;
+                }
+            }
+            MLtStatement::IfStatement(_, body) | MLtStatement::PersistentInit(_, body) => {
+                count_assignments(body, counts)
+            }
+            _ => {}
+        }
+    }
+}
+
+struct PropagationState<'a> {
+    constants: HashMap<String, f64>,
+    assignment_counts: HashMap<String, u32>,
+    non_constant: Vec<String>, // params, persistents and the return value
+    options: &'a Options,
+}
+
+fn fold_statement_list(
+    statements: Vec<MLtStatement>,
+    state: &mut PropagationState,
+    top_level: bool,
+) -> Vec<MLtStatement> {
+    let mut folded = vec![];
+    for statement in statements {
+        let keep_names = state.options.keep_constexpr;
+        match statement {
+            MLtStatement::Assignment(lvalue, expr) => {
+                let (expr, value) = fold_expression(expr, &state.constants, keep_names);
+                match (&lvalue, value) {
+                    (MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)), Some(value))
+                        if top_level
+                            && state.assignment_counts.get(name) == Some(&1)
+                            && !state.non_constant.contains(name) =>
+                    {
+                        state.constants.insert(name.clone(), value);
+                        if keep_names {
+                            folded.push(MLtStatement::Constant(name.clone(), expr));
+                        }
+                    }
+                    _ => folded.push(MLtStatement::Assignment(lvalue, expr)),
+                }
+            }
+            MLtStatement::MultiAssignment(lvalues, expr) => {
+                folded.push(MLtStatement::MultiAssignment(
+                    lvalues,
+                    fold_expression(expr, &state.constants, keep_names).0,
+                ))
+            }
+            MLtStatement::IfStatement(cond, body) => {
+                match fold_expression(cond, &state.constants, keep_names) {
+                    // provably true, the body always runs so it stays at this level
+                    (_, Some(value)) if value != 0.0 => {
+                        folded.extend(fold_statement_list(body, state, top_level))
+                    }
+                    // provably false, the body never runs
+                    (_, Some(_)) => {}
+                    (cond, None) => folded.push(MLtStatement::IfStatement(
+                        cond,
+                        fold_statement_list(body, state, false),
+                    )),
+                }
+            }
+            MLtStatement::Temporary(name, expr) => folded.push(MLtStatement::Temporary(
+                name,
+                fold_expression(expr, &state.constants, keep_names).0,
+            )),
+            MLtStatement::PersistentInit(name, body) => folded.push(MLtStatement::PersistentInit(
+                name,
+                fold_statement_list(body, state, false),
+            )),
+            statement => folded.push(statement),
+        }
+    }
+    folded
+}
+
+pub fn propagate_constants(mut function: MLtFunction, options: &Options) -> MLtFunction {
+    let mut assignment_counts = HashMap::new();
+    count_assignments(&function.body, &mut assignment_counts);
+
+    let mut non_constant: Vec<String> = function
+        .params
+        .iter()
+        .map(|p| p.strip_prefix("&").unwrap_or(p).to_string())
+        .collect();
+    non_constant.push(function.return_obj.clone());
+
+    let mut state = PropagationState {
+        constants: HashMap::new(),
+        assignment_counts,
+        non_constant,
+        options,
+    };
+    function.body = fold_statement_list(function.body, &mut state, true);
+    function
+}
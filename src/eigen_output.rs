@@ -1,13 +1,84 @@
+use crate::aliasing::{compound_update, reads_variable, top_level_product, written_variable};
+use crate::builtins;
+use crate::common_subexpressions::{statement_expressions, visit_subexpressions};
+use crate::options::{Options, ScalarType};
+use crate::renaming::rename_shape_changes;
 use crate::syntax::*;
-use crate::type_inference::{expr_type, inline_matrix_type, lvalue_type};
-use std::collections::HashMap;
+use crate::type_inference::{
+    Dim, ElemType, MLtType, Reduction, annotation_type, assignment_type, branch_declarations,
+    check_annotation, declared_name, declared_types, dim_argument, expr_type, inline_matrix_type,
+    lvalue_type, multi_output_types, reduction_kind, return_type, shape_argument_exprs,
+    shape_arguments, signature_annotations, size_dim,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::{fs::File, io::Write};
 
-fn type_to_cpp((rows, cols): (u32, u32)) -> String {
-    match (rows, cols) {
-        (1, 1) => "float".to_string(),
-        (rows, 1) => format!("Vector{}", rows),
-        (rows, cols) => format!("Matrix{}_{}", rows, cols),
+fn scalar_to_cpp(options: &Options) -> &'static str {
+    match options.scalar {
+        ScalarType::Float => "float",
+        ScalarType::Double => "double",
+        ScalarType::Template => "Scalar",
+    }
+}
+
+// fixed size typedefs are named like eigen's own (`Vector3f`, `Matrix3_4cd`), and are expected to
+// be defined in `matlab_funcs.h` - the templated version and symbolic sizes spell out the eigen type
+fn type_to_cpp(t: &MLtType, options: &Options) -> String {
+    let scalar = match t.elem {
+        ElemType::Bool => "bool".to_string(),
+        ElemType::Int => "int".to_string(),
+        ElemType::Real => scalar_to_cpp(options).to_string(),
+        ElemType::Complex => format!("std::complex<{}>", scalar_to_cpp(options)),
+    };
+    if t.is_scalar() {
+        return scalar;
+    }
+    let suffix = match (t.elem, options.scalar) {
+        // bool matrices come from comparisons, which eigen only does on arrays
+        (ElemType::Bool, _) => {
+            return format!("Eigen::Array<bool, {}, {}>", t.rows, t.cols);
+        }
+        (ElemType::Int, _) | (_, ScalarType::Template) => {
+            return format!("Eigen::Matrix<{}, {}, {}>", scalar, t.rows, t.cols);
+        }
+        (ElemType::Real, ScalarType::Float) => "f",
+        (ElemType::Real, _) => "d",
+        (ElemType::Complex, ScalarType::Float) => "cf",
+        (ElemType::Complex, _) => "cd",
+    };
+    match (&t.rows, &t.cols) {
+        (Dim::Fixed(rows), Dim::Fixed(1)) => format!("Vector{}{}", rows, suffix),
+        (Dim::Fixed(rows), Dim::Fixed(cols)) => format!("Matrix{}_{}{}", rows, cols, suffix),
+        (Dim::Dynamic, Dim::Fixed(1)) => format!("VectorX{}", suffix),
+        (Dim::Fixed(1), Dim::Dynamic) => format!("RowVectorX{}", suffix),
+        (Dim::Dynamic, Dim::Dynamic) => format!("MatrixX{}", suffix),
+        (rows, cols) => format!("Eigen::Matrix<{}, {}, {}>", scalar, rows, cols),
+    }
+}
+
+// float literals need a suffix, or eigen won't mix them with float matrices
+fn float_literal_to_cpp(value: &str, options: &Options) -> String {
+    match options.scalar {
+        ScalarType::Float => format!("{}f", value),
+        ScalarType::Double => value.to_string(),
+        ScalarType::Template => format!("Scalar({})", value),
+    }
+}
+
+fn quaternion_type_to_cpp(options: &Options) -> &'static str {
+    match options.scalar {
+        ScalarType::Float => "Eigen::Quaternionf",
+        ScalarType::Double => "Eigen::Quaterniond",
+        ScalarType::Template => "Eigen::Quaternion<Scalar>",
+    }
+}
+
+// the generated helpers are templates over the scalar type in templated output, which can't be
+// deduced from expression arguments, so it is passed explicitly
+fn helper_to_cpp(name: &str, options: &Options) -> String {
+    match options.scalar {
+        ScalarType::Template => format!("{}<Scalar>", name),
+        _ => name.to_string(),
     }
 }
 
@@ -47,30 +118,24 @@ fn matrix_to_cpp(matrix: MLtMatrixAccess) -> String {
 fn function_to_dot_function(
     function_name: &str,
     function_params: Vec<MLtExpr>,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
     let fname_map = HashMap::from([
         ("diag", "asDiagonal()"),
-        ("abs", "cwiseAbs()"),
         ("norm", "norm()"),
-        ("exp", "array().exp().matrix()"),
+        ("trace", "trace()"),
+        ("det", "determinant()"),
     ]);
     let dot_name = fname_map
         .get(function_name)
-        .expect(format!("missing {} in dot_name map", function_name).as_str());
+        .unwrap_or_else(|| panic!("missing {} in dot_name map", function_name));
     match function_params.as_slice() {
-        [MLtExpr::Basic(lvalue)] => {
-            format!(
-                "{}.{}",
-                lvalue_to_cpp(lvalue.clone(), ti_state, line_num),
-                dot_name
-            )
-        }
         [expr] => {
             format!(
-                "({}).{}",
-                expr_to_cpp(expr.clone(), ti_state, line_num),
+                "{}.{}",
+                operand_to_cpp(expr.clone(), ti_state, line_num, options),
                 dot_name
             )
         }
@@ -80,100 +145,743 @@ fn function_to_dot_function(
     }
 }
 
-fn function_call_to_cpp(
-    function_name: String,
+// lvalues can have a method called on them directly, anything else needs parentheses
+fn operand_to_cpp(
+    expr: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let is_operand = matches!(expr, MLtExpr::Basic(_) | MLtExpr::Parenthesized(_));
+    as_operand(is_operand, expr_to_cpp(expr, ti_state, line_num, options))
+}
+
+fn as_operand(is_operand: bool, cpp: String) -> String {
+    match is_operand {
+        true => cpp,
+        false => format!("({})", cpp),
+    }
+}
+
+// fixed size and symbolic constructors like `Identity()` know their size, dynamic ones are given
+// the size of `like`
+fn constructor_args_to_cpp(t: &MLtType, like: &str, args: &str) -> String {
+    let mut all_args = vec![];
+    if t.is_dynamic() {
+        all_args.push(format!("{}.rows(), {}.cols()", like, like));
+    }
+    if !args.is_empty() {
+        all_args.push(args.to_string());
+    }
+    all_args.join(", ")
+}
+
+// dimensions that are only known at runtime are read from `runtime`, like `x.rows()`
+fn dim_to_cpp(dim: &Dim, runtime: String) -> String {
+    match dim {
+        Dim::Dynamic => runtime,
+        dim => dim.to_string(),
+    }
+}
+
+// a size or count argument, folded to a literal when it is known
+fn dim_argument_to_cpp(
+    expr: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let dim = dim_argument(&expr, ti_state, line_num, false);
+    dim_to_cpp(&dim, expr_to_cpp(expr, ti_state, line_num, options))
+}
+
+fn size_to_cpp(
+    function_name: &str,
     function_params: Vec<MLtExpr>,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
-    match function_name.as_str() {
-        "eye" => {
-            if let Some(MLtExpr::Basic(MLtLValue::Integer(n))) = function_params.get(0) {
-                let n = n.parse().expect("Argument to eye must be an int");
-                format!("{}::Identity()", type_to_cpp((n, n)))
-            } else {
-                panic!("eye expects one integer argument");
+    let Some(x) = function_params.first().cloned() else {
+        panic!("{} expects a matrix argument", function_name);
+    };
+    // `size(x)` is a row of both sizes
+    if function_name == "size" && function_params.len() == 1 {
+        let (rows, cols) = expr_type(&x, ti_state, line_num, false).shape();
+        let x = operand_to_cpp(x, ti_state, line_num, options);
+        return format!(
+            "({}() << {}, {}).finished()",
+            type_to_cpp(&MLtType::real((1, 2)), options),
+            dim_to_cpp(&rows, format!("{}.rows()", x)),
+            dim_to_cpp(&cols, format!("{}.cols()", x))
+        );
+    }
+    let dim = size_dim(function_name, &function_params, ti_state, line_num, false)
+        .unwrap_or_else(|| panic!("{} expects a matrix argument", function_name));
+    let x = operand_to_cpp(x, ti_state, line_num, options);
+    let runtime = match (function_name, function_params.get(1)) {
+        ("size", Some(MLtExpr::Basic(MLtLValue::Integer(dim)))) if dim == "1" => {
+            format!("{}.rows()", x)
+        }
+        ("size", _) => format!("{}.cols()", x),
+        ("numel", _) => format!("{}.size()", x),
+        _ => format!("std::max({0}.rows(), {0}.cols())", x),
+    };
+    dim_to_cpp(&dim, runtime)
+}
+
+// the diagonal blocks with zero blocks around them, in a comma initializer
+fn blkdiag_to_cpp(
+    function_params: Vec<MLtExpr>,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let result = lvalue_type(
+        &MLtLValue::FunctionCall("blkdiag".to_string(), function_params.clone()),
+        ti_state,
+        line_num,
+        false,
+    );
+    let mut blocks = vec![];
+    for expr in function_params {
+        let t = expr_type(&expr, ti_state, line_num, false);
+        let x = operand_to_cpp(expr, ti_state, line_num, options);
+        let rows = dim_to_cpp(&t.rows, format!("{}.rows()", x));
+        let cols = dim_to_cpp(&t.cols, format!("{}.cols()", x));
+        blocks.push((t, x, rows, cols));
+    }
+    let mut coefficients = vec![];
+    for (i, (row_type, x, rows, _)) in blocks.iter().enumerate() {
+        for (j, (col_type, _, _, cols)) in blocks.iter().enumerate() {
+            if i == j {
+                coefficients.push(x.clone());
+                continue;
             }
+            let zero = MLtType::new(result.elem, (row_type.rows.clone(), col_type.cols.clone()));
+            if zero.rows == 0 || zero.cols == 0 {
+                continue;
+            }
+            coefficients.push(match zero.is_dynamic() {
+                true => format!("{}::Zero({}, {})", type_to_cpp(&zero, options), rows, cols),
+                false => format!("{}::Zero()", type_to_cpp(&zero, options)),
+            });
         }
-        "zeros" => {
-            if let Some(MLtExpr::Basic(MLtLValue::Integer(rows))) = function_params.get(0) {
-                if let Some(MLtExpr::Basic(MLtLValue::Integer(cols))) = function_params.get(1) {
-                    let rows = rows.parse().expect("Argument to zeros must be an int");
-                    let cols = cols.parse().expect("Argument to zeros must be an int");
-                    return format!("{}::Zero()", type_to_cpp((rows, cols)));
-                } else {
-                    let rows_cols = rows.parse().expect("Argument to zeros must be an int");
-                    return format!("{}::Zero()", type_to_cpp((rows_cols, rows_cols)));
+    }
+    let size = match result.is_dynamic() {
+        true => format!(
+            "({}, {})",
+            blocks
+                .iter()
+                .map(|(_, _, rows, _)| rows.clone())
+                .collect::<Vec<_>>()
+                .join(" + "),
+            blocks
+                .iter()
+                .map(|(_, _, _, cols)| cols.clone())
+                .collect::<Vec<_>>()
+                .join(" + ")
+        ),
+        false => "()".to_string(),
+    };
+    format!(
+        "({}{} << {}).finished()",
+        type_to_cpp(&result, options),
+        size,
+        coefficients.join(", ")
+    )
+}
+
+fn is_vector((rows, cols): (Dim, Dim)) -> bool {
+    (rows == 1) != (cols == 1)
+}
+
+fn is_column_vector((rows, cols): (Dim, Dim)) -> bool {
+    cols == 1 && rows != 1
+}
+
+// `sum(x.^2)`, whose square root is the norm of x if x is a vector
+fn sum_of_squares_operand(function_params: &[MLtExpr]) -> Option<&MLtExpr> {
+    if let [MLtExpr::Basic(MLtLValue::FunctionCall(fname, sum_params))] = function_params
+        && fname == "sum"
+        && let [MLtExpr::BinOp(x, MLtBinOp::CwisePow, exponent)] = sum_params.as_slice()
+        && integer_literal(exponent) == Some(2)
+    {
+        return Some(x);
+    }
+    None
+}
+
+// exponents like `2` or `-1` that can be special cased
+fn integer_literal(expr: &MLtExpr) -> Option<i64> {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(v)) => v.parse().ok(),
+        MLtExpr::Negation(e) => integer_literal(e).map(|v| -v),
+        MLtExpr::Parenthesized(e) => integer_literal(e),
+        _ => None,
+    }
+}
+
+// `.^` - scalar exponents are applied to each coefficient, matrix exponents pair up coefficients
+fn cwise_pow_to_cpp(
+    left: MLtExpr,
+    right: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let left_scalar = expr_type(&left, ti_state, line_num, false).is_scalar();
+    let right_scalar = expr_type(&right, ti_state, line_num, false).is_scalar();
+    match (left_scalar, right_scalar) {
+        (true, true) => format!(
+            "std::pow({}, {})",
+            expr_to_cpp(left, ti_state, line_num, options),
+            expr_to_cpp(right, ti_state, line_num, options)
+        ),
+        (true, false) => format!(
+            "Eigen::pow({}, {}.array()).matrix()",
+            expr_to_cpp(left, ti_state, line_num, options),
+            operand_to_cpp(right, ti_state, line_num, options)
+        ),
+        (false, false) => format!(
+            "{}.array().pow({}.array()).matrix()",
+            operand_to_cpp(left, ti_state, line_num, options),
+            operand_to_cpp(right, ti_state, line_num, options)
+        ),
+        (false, true) => {
+            let base = operand_to_cpp(left, ti_state, line_num, options);
+            match (integer_literal(&right), &right) {
+                (Some(1), _) => base,
+                (Some(2), _) => format!("{}.cwiseAbs2()", base),
+                (Some(-1), _) => format!("{}.cwiseInverse()", base),
+                (_, MLtExpr::Basic(MLtLValue::Float(v))) if v.parse() == Ok(0.5) => {
+                    format!("{}.cwiseSqrt()", base)
                 }
+                _ => format!(
+                    "{}.array().pow({}).matrix()",
+                    base,
+                    expr_to_cpp(right, ti_state, line_num, options)
+                ),
             }
-            panic!("zeros expects two integer arguments");
-        }
-        "ones" => {
-            if let Some(MLtExpr::Basic(MLtLValue::Integer(rows))) = function_params.get(0) {
-                if let Some(MLtExpr::Basic(MLtLValue::Integer(cols))) = function_params.get(1) {
-                    let rows = rows.parse().expect("Argument to ones must be an int");
-                    let cols = cols.parse().expect("Argument to ones must be an int");
-                    return format!("{}::Ones()", type_to_cpp((rows, cols)));
-                } else {
-                    let rows_cols = rows.parse().expect("Argument to ones must be an int");
-                    return format!("{}::Ones()", type_to_cpp((rows_cols, rows_cols)));
-                }
+        }
+    }
+}
+
+// `^` - matrices must be square, small integer powers are expanded into products and anything
+// else uses MatrixPower from unsupported/Eigen/MatrixFunctions
+fn pow_to_cpp(
+    left: MLtExpr,
+    right: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let left_type = expr_type(&left, ti_state, line_num, false);
+    if left_type.is_scalar() {
+        return format!(
+            "std::pow({}, {})",
+            expr_to_cpp(left, ti_state, line_num, options),
+            expr_to_cpp(right, ti_state, line_num, options)
+        );
+    }
+    let simple_base = matches!(left, MLtExpr::Basic(_));
+    let base = operand_to_cpp(left, ti_state, line_num, options);
+    match integer_literal(&right) {
+        Some(0) => format!(
+            "{}::Identity({})",
+            type_to_cpp(&left_type, options),
+            constructor_args_to_cpp(&left_type, &base, "")
+        ),
+        Some(1) => base,
+        Some(-1) => format!("{}.inverse()", base),
+        // only repeat simple operands so the expression isn't evaluated several times
+        Some(n @ 2..=4) if simple_base => format!("({})", vec![base; n as usize].join(" * ")),
+        _ => format!(
+            "{}.pow({})",
+            base,
+            expr_to_cpp(right, ti_state, line_num, options)
+        ),
+    }
+}
+
+// scalars use the std:: function, matrices use the eigen array op
+fn elementwise_to_cpp(
+    function_name: &str,
+    function_params: Vec<MLtExpr>,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let types: Vec<MLtType> = function_params
+        .iter()
+        .map(|p| expr_type(p, ti_state, line_num, false))
+        .collect();
+    // each argument is converted once, and parenthesized where a method is called on it
+    let is_operand: Vec<bool> = function_params
+        .iter()
+        .map(|p| matches!(p, MLtExpr::Basic(_) | MLtExpr::Parenthesized(_)))
+        .collect();
+    let scalars: Vec<bool> = types.iter().map(|t| t.is_scalar()).collect();
+    let raw_operands: Vec<String> = function_params
+        .into_iter()
+        .map(|p| expr_to_cpp(p, ti_state, line_num, options))
+        .collect();
+    let operands: Vec<String> = raw_operands
+        .iter()
+        .zip(is_operand)
+        .map(|(raw, is_operand)| as_operand(is_operand, raw.clone()))
+        .collect();
+
+    if let Some((scalar_form, matrix_form)) = builtins::unary_elementwise(function_name) {
+        return match scalars.as_slice() {
+            // std::conj of a real number is complex
+            [true] if function_name == "conj" && types[0].elem != ElemType::Complex => {
+                raw_operands[0].clone()
             }
-            panic!("ones expects two integer arguments");
+            [true] => builtins::scalar_form_to_cpp(scalar_form, &raw_operands, &operands),
+            [_] => matrix_form.replace("{0}", &operands[0]),
+            _ => panic!("{} expects one argument", function_name),
+        };
+    }
+
+    let (scalar_form, lambda_body) = builtins::binary_elementwise(function_name)
+        .unwrap_or_else(|| panic!("missing {} in elementwise builtins", function_name));
+    let lambda = format!("[](auto a, auto b) {{ return {}; }}", lambda_body);
+    match scalars.as_slice() {
+        [true, true] => builtins::scalar_form_to_cpp(scalar_form, &raw_operands, &operands),
+        // broadcast the scalar to the shape of the matrix
+        [true, false] => format!(
+            "{}::Constant({}).binaryExpr({}, {})",
+            type_to_cpp(&types[1], options),
+            constructor_args_to_cpp(&types[1], &operands[1], &raw_operands[0]),
+            raw_operands[1],
+            lambda
+        ),
+        [false, true] => format!(
+            "{}.binaryExpr({}::Constant({}), {})",
+            operands[0],
+            type_to_cpp(&types[0], options),
+            constructor_args_to_cpp(&types[0], &operands[0], &raw_operands[1]),
+            lambda
+        ),
+        [_, _] => format!(
+            "{}.binaryExpr({}, {})",
+            operands[0], raw_operands[1], lambda
+        ),
+        _ => panic!("{} expects two arguments", function_name),
+    }
+}
+
+fn reduction_to_cpp(
+    function_name: &str,
+    function_params: Vec<MLtExpr>,
+    dim_idx: usize,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let fname_map = HashMap::from([
+        ("sum", "sum()"),
+        ("prod", "prod()"),
+        ("mean", "mean()"),
+        ("any", "any()"),
+        ("all", "all()"),
+        ("min", "minCoeff()"),
+        ("max", "maxCoeff()"),
+    ]);
+    let dot_name = fname_map
+        .get(function_name)
+        .unwrap_or_else(|| panic!("missing {} in reduction map", function_name));
+    let expr = function_params
+        .first()
+        .unwrap_or_else(|| panic!("{} expects at least one argument", function_name))
+        .clone();
+    let expr_type = expr_type(&expr, ti_state, line_num, false);
+    let expr_shape = expr_type.shape();
+    let is_scalar = expr_type.is_scalar();
+    // eigen sums bools as bools
+    let dot_name = match function_name {
+        "sum" if expr_type.elem == ElemType::Bool => "count()",
+        _ => dot_name,
+    };
+    let reduction = reduction_kind(expr_shape, function_params.get(dim_idx));
+    let operand = operand_to_cpp(expr, ti_state, line_num, options);
+    match reduction {
+        // reducing a scalar is a no-op, except any|all which become a comparison
+        Reduction::Full if is_scalar => match function_name {
+            "any" | "all" => format!("({} != 0)", operand),
+            _ => operand,
+        },
+        Reduction::Full => format!("{}.{}", operand, dot_name),
+        Reduction::Colwise => format!("{}.colwise().{}", operand, dot_name),
+        Reduction::Rowwise => format!("{}.rowwise().{}", operand, dot_name),
+        // reducing along a dimension the matrix doesn't have leaves it as is
+        Reduction::None => match function_name {
+            "any" | "all" if expr_type.elem == ElemType::Bool => operand,
+            "any" | "all" if is_scalar => format!("({} != 0)", operand),
+            "any" | "all" => format!("({}.array() != 0)", operand),
+            _ => operand,
+        },
+    }
+}
+
+// nested quaternion builtins stay as eigen quaternions instead of converting back and forth
+fn quaternion_operand_to_cpp(
+    expr: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    match expr {
+        MLtExpr::Basic(MLtLValue::FunctionCall(function_name, function_params))
+            if builtins::is_quaternion(&function_name) && function_name != "quat2rotm" =>
+        {
+            quaternion_value_to_cpp(&function_name, function_params, ti_state, line_num, options)
+        }
+        expr => format!(
+            "{}({})",
+            helper_to_cpp("quatFromMatlab", options),
+            expr_to_cpp(expr, ti_state, line_num, options)
+        ),
+    }
+}
+
+// the eigen quaternion result of a quaternion builtin that returns a quaternion
+fn quaternion_value_to_cpp(
+    function_name: &str,
+    function_params: Vec<MLtExpr>,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    match (function_name, function_params.as_slice()) {
+        ("quatmultiply", [left, right]) => format!(
+            "({} * {})",
+            quaternion_operand_to_cpp(left.clone(), ti_state, line_num, options),
+            quaternion_operand_to_cpp(right.clone(), ti_state, line_num, options)
+        ),
+        ("quatconj" | "quatinv" | "quatnormalize", [q]) => {
+            let method = match function_name {
+                "quatconj" => "conjugate()",
+                "quatinv" => "inverse()",
+                _ => "normalized()",
+            };
+            format!(
+                "{}.{}",
+                quaternion_operand_to_cpp(q.clone(), ti_state, line_num, options),
+                method
+            )
+        }
+        // the argument is a rotation matrix, not a quaternion
+        ("rotm2quat", [rotm]) => format!(
+            "{}({})",
+            quaternion_type_to_cpp(options),
+            expr_to_cpp(rotm.clone(), ti_state, line_num, options)
+        ),
+        (fname, _) => panic!("{} called with the wrong number of arguments", fname),
+    }
+}
+
+fn quaternion_to_cpp(
+    function_name: &str,
+    function_params: Vec<MLtExpr>,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    match (function_name, function_params.as_slice()) {
+        ("quat2rotm", [q]) => format!(
+            "{}.toRotationMatrix()",
+            quaternion_operand_to_cpp(q.clone(), ti_state, line_num, options)
+        ),
+        ("quat2rotm", _) => panic!("quat2rotm expects one quaternion argument"),
+        _ => format!(
+            "{}({})",
+            helper_to_cpp("quatToMatlab", options),
+            quaternion_value_to_cpp(function_name, function_params, ti_state, line_num, options)
+        ),
+    }
+}
+
+fn function_call_to_cpp(
+    function_name: String,
+    function_params: Vec<MLtExpr>,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    match function_name.as_str() {
+        // sizes that aren't known at compile time are passed to the constructor
+        "eye" | "zeros" | "ones" => {
+            let t = lvalue_type(
+                &MLtLValue::FunctionCall(function_name.clone(), function_params.clone()),
+                ti_state,
+                line_num,
+                false,
+            );
+            let constructor = match function_name.as_str() {
+                "eye" => "Identity",
+                "zeros" => "Zero",
+                _ => "Ones",
+            };
+            if !t.is_dynamic() {
+                return format!("{}::{}()", type_to_cpp(&t, options), constructor);
+            }
+            let (rows, cols) = shape_argument_exprs(&function_params).unwrap();
+            format!(
+                "{}::{}({}, {})",
+                type_to_cpp(&t, options),
+                constructor,
+                dim_argument_to_cpp(rows, ti_state, line_num, options),
+                dim_argument_to_cpp(cols, ti_state, line_num, options)
+            )
         }
         "expm" => format!(
             "matrixExpPade6({})",
             function_params
                 .into_iter()
-                .map(|p| expr_to_cpp(p, ti_state, line_num))
+                .map(|p| expr_to_cpp(p, ti_state, line_num, options))
                 .collect::<Vec<_>>()
                 .join(", ")
         ),
-        "diag" | "abs" | "norm" | "exp" => {
-            function_to_dot_function(&function_name, function_params, ti_state, line_num)
+        "diag" | "norm" | "trace" | "det" => {
+            function_to_dot_function(&function_name, function_params, ti_state, line_num, options)
         }
-        "min" => {
-            if let Some(mlt_expr_l) = function_params.get(0) {
-                if let Some(mlt_expr_r) = function_params.get(1) {
-                    return format!(
-                        "{}.cwiseMin({})",
-                        expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num),
-                        expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num)
-                    );
+        "inv" | "pinv" | "eig" | "selfadjoint_eig" | "svd" | "qr" => {
+            let dot_name = match function_name.as_str() {
+                "inv" => "inverse()",
+                "pinv" => "completeOrthogonalDecomposition().pseudoInverse()",
+                // eigenvalues of a general matrix can be complex, only the real part is kept
+                "eig" => "eigenvalues().real()",
+                "selfadjoint_eig" => "selfadjointView<Eigen::Lower>().eigenvalues()",
+                // the single output form is the R factor
+                "qr" => "householderQr().matrixQR().triangularView<Eigen::Upper>()",
+                _ => "jacobiSvd().singularValues()",
+            };
+            if let [expr] = function_params.as_slice() {
+                return format!(
+                    "{}.{}",
+                    operand_to_cpp(expr.clone(), ti_state, line_num, options),
+                    dot_name
+                );
+            }
+            panic!("{} expects one matrix argument", function_name);
+        }
+        // matlab returns the upper factor unless 'lower' is passed
+        "chol" => match function_params.as_slice() {
+            [expr] => format!(
+                "{}.llt().matrixU()",
+                operand_to_cpp(expr.clone(), ti_state, line_num, options)
+            ),
+            [expr, MLtExpr::Basic(MLtLValue::Char(triangle))] if triangle == "lower" => format!(
+                "{}.llt().matrixL()",
+                operand_to_cpp(expr.clone(), ti_state, line_num, options)
+            ),
+            _ => panic!("chol expects one matrix argument and optionally 'lower'"),
+        },
+        "kron" => {
+            if let [left, right] = function_params.as_slice() {
+                return format!(
+                    "Eigen::kroneckerProduct({}, {})",
+                    expr_to_cpp(left.clone(), ti_state, line_num, options),
+                    expr_to_cpp(right.clone(), ti_state, line_num, options)
+                );
+            }
+            panic!("kron expects two matrix arguments");
+        }
+        "size" | "numel" | "length" => {
+            size_to_cpp(&function_name, function_params, ti_state, line_num, options)
+        }
+        // fixed counts replicate at compile time
+        "repmat" => {
+            let t = lvalue_type(
+                &MLtLValue::FunctionCall(function_name.clone(), function_params.clone()),
+                ti_state,
+                line_num,
+                false,
+            );
+            let (arg, counts) = function_params.split_first().unwrap();
+            let (rows, cols) = shape_arguments(counts, ti_state, line_num, false).unwrap();
+            let x = operand_to_cpp(arg.clone(), ti_state, line_num, options);
+            if !t.is_dynamic() && rows != Dim::Dynamic && cols != Dim::Dynamic {
+                return format!("{}.replicate<{}, {}>()", x, rows, cols);
+            }
+            let (rows, cols) = shape_argument_exprs(counts).unwrap();
+            format!(
+                "{}.replicate({}, {})",
+                x,
+                dim_argument_to_cpp(rows, ti_state, line_num, options),
+                dim_argument_to_cpp(cols, ti_state, line_num, options)
+            )
+        }
+        "linspace" => {
+            let t = lvalue_type(
+                &MLtLValue::FunctionCall(function_name.clone(), function_params.clone()),
+                ti_state,
+                line_num,
+                false,
+            );
+            let mut params = function_params.into_iter();
+            let (low, high) = (params.next().unwrap(), params.next().unwrap());
+            let n = match params.next() {
+                Some(n) => dim_argument_to_cpp(n, ti_state, line_num, options),
+                None => "100".to_string(),
+            };
+            format!(
+                "{}::LinSpaced({}, {}, {})",
+                type_to_cpp(&t, options),
+                n,
+                expr_to_cpp(low, ti_state, line_num, options),
+                expr_to_cpp(high, ti_state, line_num, options)
+            )
+        }
+        // variables are mapped in place, anything else is reshaped as an expression
+        "reshape" => {
+            let t = lvalue_type(
+                &MLtLValue::FunctionCall(function_name.clone(), function_params.clone()),
+                ti_state,
+                line_num,
+                false,
+            );
+            let (arg, dims) = function_params.split_first().unwrap();
+            let (rows, cols) = shape_argument_exprs(dims).unwrap();
+            let rows = dim_argument_to_cpp(rows, ti_state, line_num, options);
+            let cols = dim_argument_to_cpp(cols, ti_state, line_num, options);
+            match arg {
+                MLtExpr::Basic(
+                    MLtLValue::Matrix(MLtMatrixAccess::Matrix(_))
+                    | MLtLValue::StructMatrix(_, MLtMatrixAccess::Matrix(_)),
+                ) => {
+                    let x = operand_to_cpp(arg.clone(), ti_state, line_num, options);
+                    let size = match t.is_dynamic() {
+                        true => format!(", {}, {}", rows, cols),
+                        false => String::new(),
+                    };
+                    format!(
+                        "Eigen::Map<const {}>({}.data(){})",
+                        type_to_cpp(&t, options),
+                        x,
+                        size
+                    )
                 }
+                arg => format!(
+                    "{}.reshaped({}, {})",
+                    operand_to_cpp(arg.clone(), ti_state, line_num, options),
+                    rows,
+                    cols
+                ),
+            }
+        }
+        "blkdiag" => blkdiag_to_cpp(function_params, ti_state, line_num, options),
+        "rand" | "randn" => {
+            let t = lvalue_type(
+                &MLtLValue::FunctionCall(function_name.clone(), function_params.clone()),
+                ti_state,
+                line_num,
+                false,
+            );
+            let name = match (&options.random_functions, function_name.as_str()) {
+                (Some((rand, _)), "rand") => rand.clone(),
+                (Some((_, randn)), _) => randn.clone(),
+                (None, "rand") => helper_to_cpp("matlabRand", options),
+                (None, _) => helper_to_cpp("matlabRandn", options),
+            };
+            // the helpers take the scalar first in templated output
+            let name = match name.strip_suffix('>') {
+                Some(name) => format!("{}, {}>", name, type_to_cpp(&t, options)),
+                None => format!("{}<{}>", name, type_to_cpp(&t, options)),
+            };
+            if t.is_scalar() {
+                return format!("{}()", name);
+            }
+            let (rows, cols) = shape_argument_exprs(&function_params).unwrap();
+            format!(
+                "{}({}, {})",
+                name,
+                dim_argument_to_cpp(rows, ti_state, line_num, options),
+                dim_argument_to_cpp(cols, ti_state, line_num, options)
+            )
+        }
+        "isempty" => {
+            if let [expr] = function_params.as_slice() {
+                return format!(
+                    "({}.size() == 0)",
+                    operand_to_cpp(expr.clone(), ti_state, line_num, options)
+                );
+            }
+            panic!("isempty expects one argument");
+        }
+        "min" | "max" if function_params.len() != 2 => reduction_to_cpp(
+            &function_name,
+            function_params,
+            2,
+            ti_state,
+            line_num,
+            options,
+        ),
+        "sum" | "prod" | "mean" | "any" | "all" => reduction_to_cpp(
+            &function_name,
+            function_params,
+            1,
+            ti_state,
+            line_num,
+            options,
+        ),
+        "min" => {
+            if let Some(mlt_expr_l) = function_params.first()
+                && let Some(mlt_expr_r) = function_params.get(1)
+            {
+                return format!(
+                    "{}.cwiseMin({})",
+                    expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num, options),
+                    expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num, options)
+                );
             }
             panic!("min expects two arguments");
         }
         "max" => {
-            if let Some(mlt_expr_l) = function_params.get(0) {
-                if let Some(mlt_expr_r) = function_params.get(1) {
-                    return format!(
-                        "{}.cwiseMax({})",
-                        expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num),
-                        expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num)
-                    );
-                }
+            if let Some(mlt_expr_l) = function_params.first()
+                && let Some(mlt_expr_r) = function_params.get(1)
+            {
+                return format!(
+                    "{}.cwiseMax({})",
+                    expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num, options),
+                    expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num, options)
+                );
             }
             panic!("max expects two arguments");
         }
         "cross" => {
-            if let Some(mlt_expr_l) = function_params.get(0) {
-                if let Some(mlt_expr_r) = function_params.get(1) {
-                    return format!(
-                        "{}.cross({})",
-                        expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num),
-                        expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num)
-                    );
-                }
+            if let Some(mlt_expr_l) = function_params.first()
+                && let Some(mlt_expr_r) = function_params.get(1)
+            {
+                return format!(
+                    "{}.cross({})",
+                    expr_to_cpp(mlt_expr_l.clone(), ti_state, line_num, options),
+                    expr_to_cpp(mlt_expr_r.clone(), ti_state, line_num, options)
+                );
             }
             panic!("cross expects two arguments");
         }
+        "sqrt"
+            if sum_of_squares_operand(&function_params)
+                .is_some_and(|x| is_vector(expr_type(x, ti_state, line_num, false).shape())) =>
+        {
+            let x = sum_of_squares_operand(&function_params).unwrap().clone();
+            format!("{}.norm()", operand_to_cpp(x, ti_state, line_num, options))
+        }
+        fname if builtins::is_elementwise(fname) => {
+            elementwise_to_cpp(fname, function_params, ti_state, line_num, options)
+        }
+        fname if options.quaternions && builtins::is_quaternion(fname) => {
+            quaternion_to_cpp(fname, function_params, ti_state, line_num, options)
+        }
         _ => format!(
             "{}({})",
             function_name,
             function_params
                 .into_iter()
-                .map(|p| expr_to_cpp(p, ti_state, line_num))
+                .map(|p| expr_to_cpp(p, ti_state, line_num, options))
                 .collect::<Vec<_>>()
                 .join(", ")
         ),
@@ -182,26 +890,44 @@ fn function_call_to_cpp(
 
 fn lvalue_to_cpp(
     lvalue: MLtLValue,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
     match lvalue {
-        MLtLValue::Integer(val) | MLtLValue::Float(val) => format!("{}", val),
+        MLtLValue::Integer(val) => val,
+        MLtLValue::Float(val) => float_literal_to_cpp(&val, options),
+        MLtLValue::Imaginary(val) => {
+            let imaginary = match val.contains('.') || val.contains('e') {
+                true => float_literal_to_cpp(&val, options),
+                false => val,
+            };
+            format!(
+                "{}(0, {})",
+                type_to_cpp(&MLtType::fixed(ElemType::Complex, (1, 1)), options),
+                imaginary
+            )
+        }
         MLtLValue::Matrix(matrix) => matrix_to_cpp(matrix),
         MLtLValue::StructMatrix(struct_name, matrix) => {
             format!("{}.{}", struct_name, matrix_to_cpp(matrix))
         }
-        MLtLValue::InlineMatrix(mlt_exprs) => format!(
+        // the comma initializer fills the matrix row by row, like the matlab literal
+        MLtLValue::InlineMatrix(rows) => format!(
             "({}() << {}).finished()",
-            type_to_cpp(inline_matrix_type(&mlt_exprs, ti_state, line_num)),
-            mlt_exprs
-                .into_iter()
-                .map(|v| expr_to_cpp(v, ti_state, line_num))
+            type_to_cpp(
+                &inline_matrix_type(&rows, ti_state, line_num, false),
+                options
+            ),
+            rows.into_iter()
+                .flatten()
+                .map(|v| expr_to_cpp(v, ti_state, line_num, options))
                 .collect::<Vec<_>>()
                 .join(", ")
         ),
+        MLtLValue::Char(chars) => format!("\"{}\"", chars),
         MLtLValue::FunctionCall(function_name, function_params) => {
-            function_call_to_cpp(function_name, function_params, ti_state, line_num)
+            function_call_to_cpp(function_name, function_params, ti_state, line_num, options)
         }
     }
 }
@@ -227,82 +953,193 @@ fn binop_to_cpp(binop: MLtBinOp) -> &'static str {
     }
 }
 
+// std::complex operators only take the same scalar type, so integer literals next to complex values
+// are written as real literals (`2 + 3i` is `2.0f + std::complex<float>(0, 3)`)
+fn complex_operand(
+    expr: MLtExpr,
+    other: &MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+) -> MLtExpr {
+    let MLtExpr::Basic(MLtLValue::Integer(value)) = expr else {
+        return expr;
+    };
+    // the other side is typed again when it is converted
+    let other_elem = expr_type(other, ti_state, line_num, true).elem;
+    match other_elem {
+        ElemType::Complex => MLtExpr::Basic(MLtLValue::Float(format!("{}.0", value))),
+        _ => MLtExpr::Basic(MLtLValue::Integer(value)),
+    }
+}
+
 fn expr_to_cpp(
     expr: MLtExpr,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
     match expr {
-        MLtExpr::Basic(mlt_lvalue) => lvalue_to_cpp(mlt_lvalue, ti_state, line_num),
+        MLtExpr::Basic(mlt_lvalue) => lvalue_to_cpp(mlt_lvalue, ti_state, line_num, options),
         MLtExpr::Negation(mlt_expr) => {
-            format!("-{}", expr_to_cpp(*mlt_expr, ti_state, line_num))
+            format!("-{}", expr_to_cpp(*mlt_expr, ti_state, line_num, options))
         }
+        // matlab's `'` is the conjugate transpose
         MLtExpr::Transposed(mlt_expr) => {
-            format!("{}.transpose()", expr_to_cpp(*mlt_expr, ti_state, line_num))
+            let transpose = match expr_type(&mlt_expr, ti_state, line_num, true).elem {
+                ElemType::Complex => "adjoint",
+                _ => "transpose",
+            };
+            format!(
+                "{}.{}()",
+                expr_to_cpp(*mlt_expr, ti_state, line_num, options),
+                transpose
+            )
         }
+        MLtExpr::PlainTransposed(mlt_expr) => format!(
+            "{}.transpose()",
+            expr_to_cpp(*mlt_expr, ti_state, line_num, options)
+        ),
         MLtExpr::Parenthesized(mlt_expr) => {
-            format!("({})", expr_to_cpp(*mlt_expr, ti_state, line_num))
+            format!("({})", expr_to_cpp(*mlt_expr, ti_state, line_num, options))
+        }
+        // a scalar over its norm is its sign
+        MLtExpr::Normalized(mlt_expr)
+            if expr_type(&mlt_expr, ti_state, line_num, false).is_scalar() =>
+        {
+            let x = operand_to_cpp(*mlt_expr, ti_state, line_num, options);
+            format!("{0} / std::abs({0})", x)
+        }
+        MLtExpr::Normalized(mlt_expr) => format!(
+            "{}.normalized()",
+            operand_to_cpp(*mlt_expr, ti_state, line_num, options)
+        ),
+        MLtExpr::SquaredNorm(mlt_expr)
+            if expr_type(&mlt_expr, ti_state, line_num, false).is_scalar() =>
+        {
+            let x = operand_to_cpp(*mlt_expr, ti_state, line_num, options);
+            format!("{0} * {0}", x)
+        }
+        MLtExpr::SquaredNorm(mlt_expr) => format!(
+            "{}.squaredNorm()",
+            operand_to_cpp(*mlt_expr, ti_state, line_num, options)
+        ),
+        MLtExpr::Skew(mlt_expr) => format!(
+            "{}({})",
+            helper_to_cpp("skew", options),
+            expr_to_cpp(*mlt_expr, ti_state, line_num, options)
+        ),
+        // only the lower triangle of the middle matrix is read
+        MLtExpr::SymmetricProduct(mlt_exprl, mlt_exprm) => {
+            let middle = match *mlt_exprm {
+                MLtExpr::Basic(MLtLValue::Matrix(_) | MLtLValue::StructMatrix(_, _)) => {
+                    expr_to_cpp(*mlt_exprm, ti_state, line_num, options)
+                }
+                // selfadjointView needs direct access to the coefficients
+                expr => format!(
+                    "{}.eval()",
+                    operand_to_cpp(expr, ti_state, line_num, options)
+                ),
+            };
+            // the right side is `A'`, which conjugates a complex A
+            let transpose = match expr_type(&mlt_exprl, ti_state, line_num, true).elem {
+                ElemType::Complex => "adjoint",
+                _ => "transpose",
+            };
+            let left = operand_to_cpp(*mlt_exprl, ti_state, line_num, options);
+            format!(
+                "{} * {}.selfadjointView<Eigen::Lower>() * {}.{}()",
+                left, middle, left, transpose
+            )
         }
         MLtExpr::BinOp(mlt_exprl, mlt_bin_op, mlt_exprr) => {
+            let mlt_exprl = Box::new(complex_operand(*mlt_exprl, &mlt_exprr, ti_state, line_num));
+            let mlt_exprr = Box::new(complex_operand(*mlt_exprr, &mlt_exprl, ti_state, line_num));
             // if dividing by a matrix mul by the inverse instead
             match mlt_bin_op {
-                MLtBinOp::Div => {
-                    if expr_type(&mlt_exprr, ti_state, line_num) != (1, 1) {
-                        format!(
-                            "{} * {}.inverse()",
-                            expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                            expr_to_cpp(*mlt_exprr, ti_state, line_num)
-                        )
-                    } else {
-                        format!(
-                            "{} {} {}",
-                            expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                            binop_to_cpp(mlt_bin_op),
-                            expr_to_cpp(*mlt_exprr, ti_state, line_num)
-                        )
-                    }
+                MLtBinOp::Div if !expr_type(&mlt_exprr, ti_state, line_num, false).is_scalar() => {
+                    format!(
+                        "{} * {}.inverse()",
+                        expr_to_cpp(*mlt_exprl, ti_state, line_num, options),
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
+                    )
                 }
-                MLtBinOp::Pow => {
+                // matlab divides integers exactly
+                MLtBinOp::Div
+                    if expr_type(&mlt_exprl, ti_state, line_num, false).elem == ElemType::Int
+                        && expr_type(&mlt_exprr, ti_state, line_num, false).elem
+                            == ElemType::Int =>
+                {
                     format!(
-                        "pow({}, {})",
-                        expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                        expr_to_cpp(*mlt_exprr, ti_state, line_num)
+                        "static_cast<{}>({}) / {}",
+                        scalar_to_cpp(options),
+                        expr_to_cpp(*mlt_exprl, ti_state, line_num, options),
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
+                    )
+                }
+                MLtBinOp::Pow => pow_to_cpp(*mlt_exprl, *mlt_exprr, ti_state, line_num, options),
+                MLtBinOp::EqualTo
+                | MLtBinOp::NotEqualTo
+                | MLtBinOp::LessThan
+                | MLtBinOp::LessThanEqualTo
+                | MLtBinOp::GreaterThan
+                | MLtBinOp::GreaterThanEqualTo
+                    if !expr_type(&mlt_exprl, ti_state, line_num, false).is_scalar()
+                        || !expr_type(&mlt_exprr, ti_state, line_num, false).is_scalar() =>
+                {
+                    comparison_to_cpp(
+                        *mlt_exprl, mlt_bin_op, *mlt_exprr, ti_state, line_num, options,
+                    )
+                }
+                // skew(v) * u is the cross product
+                MLtBinOp::Mul
+                    if matches!(*mlt_exprl, MLtExpr::Skew(_))
+                        && expr_type(&mlt_exprr, ti_state, line_num, false).fixed_shape()
+                            == Some((3, 1)) =>
+                {
+                    let MLtExpr::Skew(v) = *mlt_exprl else {
+                        unreachable!()
+                    };
+                    format!(
+                        "{}.cross({})",
+                        operand_to_cpp(*v, ti_state, line_num, options),
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
+                    )
+                }
+                // x' * x on a column vector, on a row vector it's the outer product
+                MLtBinOp::Mul
+                    if matches!(&*mlt_exprl, MLtExpr::Transposed(x) if *x == mlt_exprr)
+                        && is_column_vector(
+                            expr_type(&mlt_exprr, ti_state, line_num, false).shape(),
+                        ) =>
+                {
+                    format!(
+                        "{}.squaredNorm()",
+                        operand_to_cpp(*mlt_exprr, ti_state, line_num, options)
                     )
                 }
                 MLtBinOp::CwiseMul => {
                     format!(
                         "{}.cwiseProduct({})",
-                        expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                        expr_to_cpp(*mlt_exprr, ti_state, line_num)
+                        expr_to_cpp(*mlt_exprl, ti_state, line_num, options),
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
                     )
                 }
                 MLtBinOp::CwiseDiv => {
                     format!(
                         "{}.cwiseQuotient({})",
-                        expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                        expr_to_cpp(*mlt_exprr, ti_state, line_num)
+                        expr_to_cpp(*mlt_exprl, ti_state, line_num, options),
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
                     )
                 }
                 MLtBinOp::CwisePow => {
-                    match *mlt_exprr {
-                        MLtExpr::Basic(MLtLValue::Integer(v)) => {
-                            if v == "2" {
-                                return format!(
-                                    "{}.cwiseAbs2()",
-                                    expr_to_cpp(*mlt_exprl, ti_state, line_num),
-                                );
-                            }
-                        }
-                        _ => {}
-                    }
-                    panic!("CwisePow only supports .^2");
+                    cwise_pow_to_cpp(*mlt_exprl, *mlt_exprr, ti_state, line_num, options)
                 }
                 _ => {
                     format!(
                         "{} {} {}",
-                        expr_to_cpp(*mlt_exprl, ti_state, line_num),
+                        expr_to_cpp(*mlt_exprl, ti_state, line_num, options),
                         binop_to_cpp(mlt_bin_op),
-                        expr_to_cpp(*mlt_exprr, ti_state, line_num)
+                        expr_to_cpp(*mlt_exprr, ti_state, line_num, options)
                     )
                 }
             }
@@ -310,6 +1147,48 @@ fn expr_to_cpp(
     }
 }
 
+// matlab's `if` on a matrix runs when every element is nonzero
+fn condition_to_cpp(
+    expr: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let condition_type = expr_type(&expr, ti_state, line_num, false);
+    if condition_type.is_scalar() {
+        return expr_to_cpp(expr, ti_state, line_num, options);
+    }
+    let operand = operand_to_cpp(expr, ti_state, line_num, options);
+    match condition_type.elem {
+        ElemType::Bool => format!("{}.all()", operand),
+        _ => format!("({}.array() != 0).all()", operand),
+    }
+}
+
+// comparisons are elementwise, and eigen only compares arrays
+fn comparison_to_cpp(
+    left: MLtExpr,
+    op: MLtBinOp,
+    right: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let mut side_to_cpp = |expr: MLtExpr| {
+        if expr_type(&expr, ti_state, line_num, false).is_scalar() {
+            expr_to_cpp(expr, ti_state, line_num, options)
+        } else {
+            format!(
+                "{}.array()",
+                operand_to_cpp(expr, ti_state, line_num, options)
+            )
+        }
+    };
+    let left_cpp = side_to_cpp(left);
+    let right_cpp = side_to_cpp(right);
+    format!("{} {} {}", left_cpp, binop_to_cpp(op), right_cpp)
+}
+
 fn matrix_access_should_have_type(matrix: &MLtMatrixAccess) -> bool {
     match matrix {
         MLtMatrixAccess::Matrix(_) => true,
@@ -322,54 +1201,418 @@ fn matrix_access_should_have_type(matrix: &MLtMatrixAccess) -> bool {
 
 fn lvalue_is_simple_matrix(lvalve: &MLtLValue) -> bool {
     match lvalve {
-        MLtLValue::Integer(_) | MLtLValue::Float(_) => false,
+        MLtLValue::Integer(_) | MLtLValue::Float(_) | MLtLValue::Imaginary(_) => false,
         MLtLValue::Matrix(mlt_matrix_access) => matrix_access_should_have_type(mlt_matrix_access),
         MLtLValue::StructMatrix(_, mlt_matrix_access) => {
             matrix_access_should_have_type(mlt_matrix_access)
         }
         MLtLValue::InlineMatrix(_) => false,
+        MLtLValue::Char(_) => false,
         MLtLValue::FunctionCall(_, _) => false,
     }
 }
 
+// the shapes have to match, and storing a wider element type (a real in an int) loses information
+fn check_assignment_types(left_side_type: MLtType, right_side_type: MLtType, line_num: &u32) {
+    if left_side_type.conflicts(&right_side_type) {
+        println!(
+            "Assignment type warning: left side type does not match right side type: ({}, {}) != ({}, {}) on line {}.",
+            left_side_type.rows,
+            left_side_type.cols,
+            right_side_type.rows,
+            right_side_type.cols,
+            line_num
+        )
+    } else if left_side_type.elem < right_side_type.elem {
+        println!(
+            "Assignment type warning: {:?} value is narrowed to {:?} on line {}.",
+            right_side_type.elem, left_side_type.elem, line_num
+        )
+    }
+}
+
+fn assignment_to_cpp(
+    lvalue: MLtLValue,
+    right_side_type: MLtType,
+    right_side_cpp: String,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let simple_matrix = lvalue_is_simple_matrix(&lvalue); // we don't place types on matrix accesses
+    let left_side_cpp = lvalue_to_cpp(lvalue.clone(), ti_state, line_num, options);
+
+    // don't apply type if we already have a type recorded
+    if simple_matrix && !ti_state.contains_key(&left_side_cpp) {
+        ti_state.insert(left_side_cpp.clone(), right_side_type.clone());
+        format!(
+            "{} {} = {};",
+            type_to_cpp(&right_side_type, options),
+            left_side_cpp,
+            right_side_cpp
+        )
+    } else {
+        let left_side_type = lvalue_type(&lvalue, ti_state, line_num, false);
+        // renaming has already handled the variables that can change shape
+        if simple_matrix && left_side_type.conflicts(&right_side_type) {
+            return shape_change_error(
+                &left_side_cpp,
+                &right_side_cpp,
+                &left_side_type,
+                &right_side_type,
+                line_num,
+            );
+        }
+        check_assignment_types(left_side_type, right_side_type, line_num);
+        format!("{} = {};", left_side_cpp, right_side_cpp)
+    }
+}
+
+// an assignment that changes the shape of a variable that can't be renamed stops the C++ from
+// compiling, rather than being assigned into the wrong type
+fn shape_change_error(
+    left_side_cpp: &str,
+    right_side_cpp: &str,
+    left_side_type: &MLtType,
+    right_side_type: &MLtType,
+    line_num: &u32,
+) -> String {
+    println!(
+        "Shape change error: {} is {} by {} but is assigned a {} by {} on line {}, only variables outside of if statements that aren't passed by reference can change shape.",
+        left_side_cpp,
+        left_side_type.rows,
+        left_side_type.cols,
+        right_side_type.rows,
+        right_side_type.cols,
+        line_num
+    );
+    format!(
+        "#error \"{} = {}; changes the shape of {} from {} by {} to {} by {}\"",
+        left_side_cpp,
+        right_side_cpp.replace('"', "'"),
+        left_side_cpp,
+        left_side_type.rows,
+        left_side_type.cols,
+        right_side_type.rows,
+        right_side_type.cols
+    )
+}
+
+// checks the types already recorded instead of inferring them, so it doesn't repeat warnings
+fn is_known_scalar(expr: &MLtExpr, ti_state: &HashMap<String, MLtType>) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(_) | MLtLValue::Float(_) | MLtLValue::Imaginary(_)) => {
+            true
+        }
+        MLtExpr::Basic(
+            lvalue @ (MLtLValue::Matrix(MLtMatrixAccess::Matrix(_))
+            | MLtLValue::StructMatrix(_, MLtMatrixAccess::Matrix(_))),
+        ) => written_variable(lvalue)
+            .and_then(|name| ti_state.get(&name))
+            .is_some_and(|t| t.is_scalar()),
+        MLtExpr::Basic(
+            MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(_, _))
+            | MLtLValue::StructMatrix(_, MLtMatrixAccess::MatrixIndex(_, _)),
+        ) => true,
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e) => is_known_scalar(e, ti_state),
+        MLtExpr::BinOp(left, _, right) => {
+            is_known_scalar(left, ti_state) && is_known_scalar(right, ti_state)
+        }
+        _ => false,
+    }
+}
+
+fn is_matrix_product(expr: &MLtExpr, ti_state: &HashMap<String, MLtType>) -> bool {
+    match expr {
+        MLtExpr::BinOp(left, MLtBinOp::Mul, right) => {
+            !is_known_scalar(left, ti_state) && !is_known_scalar(right, ti_state)
+        }
+        MLtExpr::SymmetricProduct(_, _) => true,
+        _ => false,
+    }
+}
+
+// updates a variable that already exists in place - `x = x + e` becomes `x += e`, and products
+// that don't read the variable being written are evaluated straight into it with `noalias()`
+// returns None for a plain assignment
+fn update_to_cpp(
+    lvalue: &MLtLValue,
+    expr: &MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> Option<String> {
+    let name = written_variable(lvalue)?;
+    if lvalue_is_simple_matrix(lvalue) && !ti_state.contains_key(&name) {
+        return None; // this is the declaration
+    }
+    let left_side_type = lvalue_type(lvalue, ti_state, line_num, false);
+
+    let (op, right_side) = match compound_update(lvalue, expr) {
+        // products are evaluated into a temporary anyway, so `P = P - K * H * P` is still safe
+        Some((op, rest))
+            if (!reads_variable(rest, &name) || is_matrix_product(rest, ti_state))
+                && (op != &MLtBinOp::Mul || is_known_scalar(rest, ti_state)) =>
+        {
+            (format!("{}=", binop_to_cpp(op.clone())), rest)
+        }
+        _ => ("=".to_string(), expr),
+    };
+    let noalias = !left_side_type.is_scalar()
+        && top_level_product(right_side).is_some_and(|p| is_matrix_product(p, ti_state))
+        && !reads_variable(right_side, &name);
+    if op == "=" && !noalias {
+        return None;
+    }
+
+    let right_side_type = expr_type(expr, ti_state, line_num, false);
+    // `P = P + e` has the type of `P`, so the shape of the update is checked on `e`
+    let update_type = expr_type(right_side, ti_state, line_num, true);
+    if lvalue_is_simple_matrix(lvalue)
+        && !update_type.is_scalar()
+        && left_side_type.conflicts(&update_type)
+    {
+        return Some(shape_change_error(
+            &lvalue_to_cpp(lvalue.clone(), ti_state, line_num, options),
+            &expr_to_cpp(expr.clone(), ti_state, line_num, options),
+            &left_side_type,
+            &update_type,
+            line_num,
+        ));
+    }
+    check_assignment_types(left_side_type, right_side_type, line_num);
+    Some(format!(
+        "{}{} {} {};",
+        lvalue_to_cpp(lvalue.clone(), ti_state, line_num, options),
+        if noalias { ".noalias()" } else { "" },
+        op,
+        expr_to_cpp(right_side.clone(), ti_state, line_num, options)
+    ))
+}
+
+// `[V, D] = eig(A)` etc - the decomposition is stored in a solver named after the outputs, which
+// each output is then read from
+fn multi_assignment_to_cpp(
+    lvalues: Vec<MLtLValue>,
+    expr: MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let MLtExpr::Basic(MLtLValue::FunctionCall(function_name, function_params)) = expr else {
+        println!("Multiple assignment error: right side must be a function call");
+        return "// multiple assignment could not be converted".to_string();
+    };
+    let output_getters: &[&str] = match function_name.as_str() {
+        "eig" => &["eigenvectors().real()", "eigenvalues().real().asDiagonal()"],
+        "selfadjoint_eig" => &["eigenvectors()", "eigenvalues().asDiagonal()"],
+        "qr" => &[
+            "householderQ()",
+            "matrixQR().triangularView<Eigen::Upper>()",
+        ],
+        "svd" => &["matrixU()", "singularValues()", "matrixV()"],
+        fname => {
+            println!(
+                "Multiple assignment error: {} does not support multiple outputs",
+                fname
+            );
+            return format!("// [...] = {}(...); // could not be converted", fname);
+        }
+    };
+    if lvalues.len() > output_getters.len() {
+        println!(
+            "Multiple assignment error: {} has at most {} outputs on line {}",
+            function_name,
+            output_getters.len(),
+            line_num
+        );
+        return format!(
+            "// [...] = {}(...); // could not be converted",
+            function_name
+        );
+    }
+
+    let arg = function_params
+        .first()
+        .unwrap_or_else(|| panic!("{} expects one matrix argument", function_name))
+        .clone();
+    let arg_type = type_to_cpp(&expr_type(&arg, ti_state, line_num, false), options);
+    let output_types =
+        multi_output_types(&function_name, &function_params, ti_state, line_num, false);
+    let solver_name = format!(
+        "{}_{}",
+        lvalues
+            .iter()
+            .map(|lvalue| lvalue_to_cpp(lvalue.clone(), ti_state, line_num, options))
+            .collect::<Vec<_>>()
+            .join("_"),
+        function_name
+    );
+    let arg_cpp = expr_to_cpp(arg, ti_state, line_num, options);
+    let solver = match function_name.as_str() {
+        "eig" => format!(
+            "Eigen::EigenSolver<{}> {}({});",
+            arg_type, solver_name, arg_cpp
+        ),
+        "selfadjoint_eig" => format!(
+            "Eigen::SelfAdjointEigenSolver<{}> {}({});",
+            arg_type, solver_name, arg_cpp
+        ),
+        "qr" => format!(
+            "Eigen::HouseholderQR<{}> {}({});",
+            arg_type, solver_name, arg_cpp
+        ),
+        _ => format!(
+            "Eigen::JacobiSVD<{}> {}({}, Eigen::ComputeFullU | Eigen::ComputeFullV);",
+            arg_type, solver_name, arg_cpp
+        ),
+    };
+
+    let mut lines = vec![solver];
+    for (idx, ((lvalue, getter), output_type)) in lvalues
+        .into_iter()
+        .zip(output_getters)
+        .zip(output_types)
+        .enumerate()
+    {
+        // matlab's S is the same shape as the input, so only the diagonal is filled in
+        if function_name == "svd" && idx == 1 {
+            let left_side_cpp = lvalue_to_cpp(lvalue.clone(), ti_state, line_num, options);
+            let size = match output_type.is_dynamic() {
+                true => format!("{0}.matrixU().rows(), {0}.matrixV().rows()", solver_name),
+                false => String::new(),
+            };
+            let zero = format!("{}::Zero({})", type_to_cpp(&output_type, options), size);
+            lines.push(assignment_to_cpp(
+                lvalue,
+                output_type,
+                zero,
+                ti_state,
+                line_num,
+                options,
+            ));
+            lines.push(format!(
+                "{}.diagonal() = {}.{};",
+                left_side_cpp, solver_name, getter
+            ));
+            continue;
+        }
+        let getter_cpp = format!("{}.{}", solver_name, getter);
+        lines.push(assignment_to_cpp(
+            lvalue,
+            output_type,
+            getter_cpp,
+            ti_state,
+            line_num,
+            options,
+        ));
+    }
+    lines.join("\n")
+}
+
+// declares the variables first assigned in a branch before it, zero initialized since the branch
+// may not run
+fn branch_declarations_to_cpp(
+    statements: &[MLtStatement],
+    ti_state: &mut HashMap<String, MLtType>,
+    declared: &HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    branch_declarations(statements, ti_state, declared, *line_num, false)
+        .into_iter()
+        .map(|(name, t)| {
+            *line_num += 1;
+            let type_str = type_to_cpp(&t, options);
+            let declaration = match (t.is_scalar(), t.elem) {
+                (true, ElemType::Bool) => format!("{} {} = false;", type_str, name),
+                (true, _) => format!("{} {} = 0;", type_str, name),
+                // dynamic matrices start out empty
+                (false, _) if t.is_dynamic() => format!("{} {};", type_str, name),
+                (false, _) => format!("{0} {1} = {0}::Zero();", type_str, name),
+            };
+            ti_state.insert(name, t);
+            declaration + "\n"
+        })
+        .collect()
+}
+
 fn generate_output_for_statement(
     statement: MLtStatement,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
+    declared: &HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
     match statement {
         MLtStatement::Assignment(lvalue, expr) => {
-            let simple_matrix = lvalue_is_simple_matrix(&lvalue); // we don't place types on matrix accesses
-            let left_side_cpp = lvalue_to_cpp(lvalue.clone(), ti_state, line_num);
-            let right_side_type = expr_type(&expr, ti_state, line_num);
-            let right_side_cpp = expr_to_cpp(expr, ti_state, line_num);
-
-            // don't apply type if we already have a type recorded
-            if simple_matrix && !ti_state.contains_key(&left_side_cpp) {
-                ti_state.insert(left_side_cpp.clone(), right_side_type);
-                format!(
-                    "{} {} = {};",
-                    type_to_cpp(right_side_type),
-                    left_side_cpp,
-                    right_side_cpp
-                )
-            } else {
-                let left_side_type = lvalue_type(&lvalue, ti_state, line_num);
-                if left_side_type != right_side_type {
-                    println!(
-                        "Assignment type warning: left side type does not match right side type: ({}, {}) != ({}, {}) on line {}.",
-                        left_side_type.0,
-                        left_side_type.1,
-                        right_side_type.0,
-                        right_side_type.1,
-                        line_num
+            match update_to_cpp(&lvalue, &expr, ti_state, line_num, options) {
+                Some(update) => update,
+                None => {
+                    let mut right_side_type = assignment_type(&expr, ti_state, line_num, false);
+                    // declared wide enough for everything assigned to it later, or as annotated
+                    if let Some(name) = declared_name(&lvalue)
+                        && !ti_state.contains_key(&name)
+                        && let Some(t) = declared.get(&name)
+                    {
+                        right_side_type = t.clone();
+                    }
+                    let right_side_cpp = expr_to_cpp(expr, ti_state, line_num, options);
+                    assignment_to_cpp(
+                        lvalue,
+                        right_side_type,
+                        right_side_cpp,
+                        ti_state,
+                        line_num,
+                        options,
                     )
                 }
-                format!("{} = {};", left_side_cpp, right_side_cpp)
             }
         }
-        MLtStatement::Normalization(matrix_name) => {
-            format!("{}.normalize();", matrix_name)
+        MLtStatement::MultiAssignment(lvalues, expr) => {
+            multi_assignment_to_cpp(lvalues, expr, ti_state, line_num, options)
+        }
+        MLtStatement::Constant(name, expr) => {
+            let expr_type = expr_type(&expr, ti_state, line_num, false);
+            ti_state.insert(name.clone(), expr_type.clone());
+            format!(
+                "constexpr {} {} = {};",
+                type_to_cpp(&expr_type, options),
+                name,
+                expr_to_cpp(expr, ti_state, line_num, options)
+            )
+        }
+        MLtStatement::Temporary(name, expr) => {
+            let right_side_type = expr_type(&expr, ti_state, line_num, false);
+            let right_side_cpp = expr_to_cpp(expr, ti_state, line_num, options);
+            let lvalue = MLtLValue::Matrix(MLtMatrixAccess::Matrix(name));
+            // temporaries don't have a line in the matlab file, so they add their own newline
+            format!(
+                "{}\n",
+                assignment_to_cpp(
+                    lvalue,
+                    right_side_type,
+                    right_side_cpp,
+                    ti_state,
+                    line_num,
+                    options
+                )
+            )
+        }
+        // floating point errors in the update can make the matrix slightly asymmetric
+        MLtStatement::Symmetrize(matrix_name) => {
+            *line_num += 1;
+            format!(
+                "{0} = ({1} * ({0} + {0}.transpose())).eval();\n",
+                matrix_name,
+                float_literal_to_cpp("0.5", options)
+            )
+        }
+        MLtStatement::Normalization(lvalue) => {
+            format!(
+                "{}.normalize();",
+                lvalue_to_cpp(lvalue, ti_state, line_num, options)
+            )
         }
         MLtStatement::Persistent(idents) => {
             *line_num += 1;
@@ -379,15 +1622,59 @@ fn generate_output_for_statement(
             )
         }
         MLtStatement::IfStatement(mlt_expr, mlt_statements) => {
+            let declarations =
+                branch_declarations_to_cpp(&mlt_statements, ti_state, declared, line_num, options);
             *line_num += 1;
             let text = format!(
-                "if ({}) {{\n{}}}",
-                expr_to_cpp(mlt_expr, ti_state, line_num),
-                // clone ti_state here to prevent types from propagating outside the if statement
-                generate_output_for_statement_list(mlt_statements, &mut ti_state.clone(), line_num)
+                "{}if ({}) {{\n{}}}",
+                declarations,
+                condition_to_cpp(mlt_expr, ti_state, line_num, options),
+                // clone ti_state here so temporaries in the branch don't propagate outside it
+                generate_output_for_statement_list(
+                    mlt_statements,
+                    &mut ti_state.clone(),
+                    declared,
+                    line_num,
+                    options
+                )
             );
             text
         }
+        MLtStatement::PersistentInit(name, mlt_statements) => {
+            let declarations =
+                branch_declarations_to_cpp(&mlt_statements, ti_state, declared, line_num, options);
+            *line_num += 1;
+            let body = generate_output_for_statement_list(
+                mlt_statements,
+                &mut ti_state.clone(),
+                declared,
+                line_num,
+                options,
+            );
+            // the flag is set on its own line after the body
+            *line_num += 1;
+            format!(
+                "{declarations}if (!{name}_initialized) {{\n{body}{name}_initialized = true;\n}}"
+            )
+        }
+        MLtStatement::TypeAnnotation(name, dims, elem) => {
+            let annotated = annotation_type(&dims, &elem);
+            match ti_state.get(&name) {
+                // parameters and variables declared before a branch already have their type
+                Some(known) => {
+                    check_annotation(&name, &annotated, known, *line_num);
+                    format!("// {}: {}", name, type_to_cpp(known, options))
+                }
+                // struct members are declared with their struct
+                None if name.contains('.') => {
+                    let comment = format!("// {}: {}", name, type_to_cpp(&annotated, options));
+                    ti_state.insert(name, annotated);
+                    comment
+                }
+                // other variables are declared with the annotated type by their first assignment
+                None => format!("// {}: {}", name, type_to_cpp(&annotated, options)),
+            }
+        }
         MLtStatement::Comment(comment_str) => {
             format!("// {}", comment_str)
         }
@@ -397,56 +1684,379 @@ fn generate_output_for_statement(
         }
         MLtStatement::NewLine => {
             *line_num += 1;
-            format!("\n")
+            "\n".to_string()
         }
     }
 }
 
 fn generate_output_for_statement_list(
     statement_list: Vec<MLtStatement>,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
+    declared: &HashMap<String, MLtType>,
     line_num: &mut u32,
+    options: &Options,
 ) -> String {
     statement_list
         .into_iter()
-        .map(|s| generate_output_for_statement(s, ti_state, line_num))
+        .map(|s| generate_output_for_statement(s, ti_state, declared, line_num, options))
         .collect()
 }
 
-fn generate_output_for_function(
-    function: MLtFunction,
-    ti_state: &mut HashMap<String, (u32, u32)>,
-    line_num: &mut u32,
-) -> String {
-    format!(
-        "{} {}({}) {{{}return {};\n}}\n",
-        type_to_cpp(
-            *ti_state
-                .get("_self")
-                .expect("ti_state should have `_self` to represent function return type")
-        ),
+// names of the persistent variables that have an `if isempty(...)` initialization block
+fn persistent_inits(statements: &[MLtStatement]) -> Vec<String> {
+    statements
+        .iter()
+        .flat_map(|statement| match statement {
+            MLtStatement::PersistentInit(name, _) => vec![name.clone()],
+            MLtStatement::IfStatement(_, body) => persistent_inits(body),
+            _ => vec![],
+        })
+        .collect()
+}
+
+// the template line and signature of a function, shared by its definition and its forward
+// declaration
+fn function_signature(
+    function: &MLtFunction,
+    ti_state: &HashMap<String, MLtType>,
+    options: &Options,
+) -> (String, String) {
+    // symbolic sizes of the parameters and return value are template parameters
+    let mut template_params = vec![];
+    if options.scalar == ScalarType::Template {
+        template_params.push("typename Scalar".to_string());
+    }
+    let mut dimension_params: Vec<String> = std::iter::once("_self")
+        .chain(
+            function
+                .params
+                .iter()
+                .map(|p| p.strip_prefix("&").unwrap_or(p)),
+        )
+        .filter_map(|name| ti_state.get(name))
+        .flat_map(|t| [t.rows.parameters(), t.cols.parameters()].concat())
+        .collect();
+    dimension_params.sort();
+    dimension_params.dedup();
+    template_params.extend(dimension_params.into_iter().map(|n| format!("int {}", n)));
+    let template = match template_params.is_empty() {
+        true => String::new(),
+        false => format!("template <{}>\n", template_params.join(", ")),
+    };
+    let initialized_flags = persistent_inits(&function.body);
+    let signature = format!(
+        "{} {}({})",
+        type_to_cpp(&ti_state["_self"], options),
         function.name,
         function
             .params
-            .into_iter()
-            .map(|p| {
-                let type_str = match ti_state.get(p.strip_prefix("&").unwrap_or(&p)) {
-                    Some(t) => type_to_cpp(*t),
-                    None => format!("{}_t", p.strip_prefix("&").unwrap_or(&p)),
+            .iter()
+            .flat_map(|p| {
+                let name = p.strip_prefix("&").unwrap_or(p).to_string();
+                let type_str = match ti_state.get(&name) {
+                    Some(t) => type_to_cpp(t, options),
+                    None => format!("{}_t", name),
                 };
-                format!("{} {}", type_str, p)
+                let mut params = vec![format!("{} {}", type_str, p)];
+                // persistent variables are passed in by reference, so their initialization flag
+                // has to live with the caller as well
+                if initialized_flags.contains(&name) {
+                    params.push(format!("bool &{}_initialized", name));
+                }
+                params
             })
             .collect::<Vec<String>>()
-            .join(", "),
-        generate_output_for_statement_list(function.body, ti_state, line_num),
+            .join(", ")
+    );
+    (template, signature)
+}
+
+// types annotated in the matlab file take precedence over the ones given in main.rs, then the
+// variables that change shape are renamed and the return type is found - warnings are printed
+// when `line_num` is given, which is once per function
+fn resolve_signature(
+    function: MLtFunction,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: Option<u32>,
+) -> MLtFunction {
+    for (name, annotated) in signature_annotations(&function) {
+        if let Some(line_num) = line_num
+            && let Some(known) = ti_state.get(&name)
+        {
+            check_annotation(&name, &annotated, known, line_num);
+        }
+        ti_state.insert(name, annotated);
+    }
+    // the return type is the type of the variable returned, so `_self` is only needed when that
+    // can't be inferred
+    let function = rename_shape_changes(function, ti_state, line_num.is_none());
+    let inferred = return_type(&function, ti_state);
+    let function_type = match (inferred, ti_state.get("_self")) {
+        (Some(inferred), Some(declared)) if !inferred.is_dynamic() => {
+            if line_num.is_some()
+                && (inferred.conflicts(declared) || inferred.elem != declared.elem)
+            {
+                println!(
+                    "Return type warning: {} returns {} as {:?} {} by {}, but `_self` is {:?} {} by {}.",
+                    function.name,
+                    function.return_obj,
+                    inferred.elem,
+                    inferred.rows,
+                    inferred.cols,
+                    declared.elem,
+                    declared.rows,
+                    declared.cols
+                );
+            }
+            inferred
+        }
+        (_, Some(declared)) => declared.clone(),
+        (Some(inferred), None) => inferred,
+        (None, None) => panic!(
+            "Couldn't infer the type of {} returned by {}, ti_state should have `_self` to represent the function return type",
+            function.return_obj, function.name
+        ),
+    };
+    ti_state.insert("_self".to_string(), function_type);
+    function
+}
+
+fn generate_output_for_function(
+    function: MLtFunction,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    options: &Options,
+) -> String {
+    let function = resolve_signature(function, ti_state, Some(*line_num));
+    let (template, signature) = function_signature(&function, ti_state, options);
+    // the template line isn't part of the function's own output, so it is counted here
+    if !template.is_empty() {
+        *line_num += 1;
+    }
+    let declared = declared_types(&function.body, ti_state, *line_num, false);
+    format!(
+        "{}{} {{{}return {};\n}}\n",
+        template,
+        signature,
+        generate_output_for_statement_list(function.body, ti_state, &declared, line_num, options),
         function.return_obj
     )
 }
 
-pub fn generate_output_file(function: MLtFunction, ti_state: &mut HashMap<String, (u32, u32)>) {
+// matlab stores quaternions scalar first as [w x y z], while eigen's coefficients are [x y z w], so
+// quaternions are converted through these at each quaternion builtin
+fn quaternion_helpers(options: &Options) -> String {
+    let row = type_to_cpp(&MLtType::real((1, 4)), options);
+    let quaternion = quaternion_type_to_cpp(options);
+    let (from_template, to_prefix) = match options.scalar {
+        ScalarType::Template => (
+            "template <typename Scalar, typename Derived>",
+            "template <typename Scalar>\n",
+        ),
+        _ => ("template <typename Derived>", "inline "),
+    };
+    [
+        from_template.to_string(),
+        format!(
+            "{} quatFromMatlab(const Eigen::MatrixBase<Derived>& q) {{",
+            quaternion
+        ),
+        format!("return {}(q(0), q(1), q(2), q(3));", quaternion),
+        "}".to_string(),
+        format!(
+            "{}{} quatToMatlab(const {}& q) {{",
+            to_prefix, row, quaternion
+        ),
+        format!("return {}(q.w(), q.x(), q.y(), q.z());", row),
+        "}".to_string(),
+        "".to_string(),
+    ]
+    .join("\n")
+        + "\n"
+}
+
+fn skew_helper(options: &Options) -> String {
+    let matrix = type_to_cpp(&MLtType::real((3, 3)), options);
+    let prefix = match options.scalar {
+        ScalarType::Template => "template <typename Scalar>\n",
+        _ => "inline ",
+    };
+    [
+        format!(
+            "{}{} skew(const {}& v) {{",
+            prefix,
+            matrix,
+            type_to_cpp(&MLtType::real((3, 1)), options)
+        ),
+        format!(
+            "return ({}() << 0, -v(2), v(1), v(2), 0, -v(0), -v(1), v(0), 0).finished();",
+            matrix
+        ),
+        "}".to_string(),
+        "".to_string(),
+    ]
+    .join("\n")
+        + "\n"
+}
+
+// rand and randn draw from one engine, matrices are filled coefficient by coefficient
+fn random_helpers(options: &Options) -> String {
+    let (prefix, scalar) = match options.scalar {
+        ScalarType::Template => ("template <typename Scalar, typename T>", "Scalar"),
+        _ => ("template <typename T>", scalar_to_cpp(options)),
+    };
+    let mut lines = vec![
+        "#include <random>".to_string(),
+        "#include <type_traits>".to_string(),
+        "inline std::mt19937& randomEngine() {".to_string(),
+        "static std::mt19937 engine;".to_string(),
+        "return engine;".to_string(),
+        "}".to_string(),
+        "template <typename T, typename Distribution>".to_string(),
+        "T randomFill(Distribution distribution, Eigen::Index rows, Eigen::Index cols) {"
+            .to_string(),
+        "if constexpr (std::is_arithmetic_v<T>) {".to_string(),
+        "return distribution(randomEngine());".to_string(),
+        "} else {".to_string(),
+        "return T::NullaryExpr(rows, cols, [&]() { return distribution(randomEngine()); });"
+            .to_string(),
+        "}".to_string(),
+        "}".to_string(),
+    ];
+    for (name, distribution) in [
+        ("matlabRand", "uniform_real_distribution"),
+        ("matlabRandn", "normal_distribution"),
+    ] {
+        lines.extend([
+            prefix.to_string(),
+            format!(
+                "T {}(Eigen::Index rows = 1, Eigen::Index cols = 1) {{",
+                name
+            ),
+            format!(
+                "return randomFill<T>(std::{}<{}>(0, 1), rows, cols);",
+                distribution, scalar
+            ),
+            "}".to_string(),
+        ]);
+    }
+    lines.push("".to_string());
+    lines.join("\n") + "\n"
+}
+
+// whether any expression in the statements (including the bodies of ifs) matches `f`
+fn statements_contain(statements: &[MLtStatement], f: &impl Fn(&MLtExpr) -> bool) -> bool {
+    statements.iter().any(|statement| {
+        let mut found = false;
+        for expr in statement_expressions(statement) {
+            visit_subexpressions(expr, &mut |e| found |= f(e));
+        }
+        found
+            || match statement {
+                MLtStatement::IfStatement(_, body) | MLtStatement::PersistentInit(_, body) => {
+                    statements_contain(body, f)
+                }
+                _ => false,
+            }
+    })
+}
+
+// external functions that declare their parameter types, so the output compiles without their
+// headers as long as they are linked in, and functions of the project called before their
+// definition, as in a cycle of calls
+fn forward_declarations(
+    functions: &[(MLtFunction, HashMap<String, MLtType>)],
+    options: &Options,
+) -> String {
+    let calls = |statements: &[MLtStatement], name: &str| {
+        statements_contain(
+            statements,
+            &|e| matches!(e, MLtExpr::Basic(MLtLValue::FunctionCall(f, _)) if f == name),
+        )
+    };
+    let prefix = match options.scalar {
+        ScalarType::Template => "template <typename Scalar>\n",
+        _ => "",
+    };
+    let mut external = BTreeMap::new();
+    for (function, ti_state) in functions {
+        for (name, t) in ti_state {
+            let Some(params) = &t.params else {
+                continue;
+            };
+            if calls(&function.body, name) && !functions.iter().any(|(f, _)| f.name == *name) {
+                external.entry(name.clone()).or_insert_with(|| {
+                    format!(
+                        "{}{} {}({});\n",
+                        prefix,
+                        type_to_cpp(t, options),
+                        name,
+                        params
+                            .iter()
+                            .map(|p| type_to_cpp(p, options))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                });
+            }
+        }
+    }
+    let mut declarations = external.into_values().collect::<String>();
+    for (idx, (function, ti_state)) in functions.iter().enumerate() {
+        if functions[..idx]
+            .iter()
+            .any(|(caller, _)| calls(&caller.body, &function.name))
+        {
+            let mut ti_state = ti_state.clone();
+            let function = resolve_signature(function.clone(), &mut ti_state, None);
+            let (template, signature) = function_signature(&function, &ti_state, options);
+            declarations += &format!("{}{};\n", template, signature);
+        }
+    }
+    match declarations.is_empty() {
+        true => String::new(),
+        false => declarations + "\n",
+    }
+}
+
+// each function is converted with its own types, in the order given
+pub fn generate_output(
+    functions: Vec<(MLtFunction, HashMap<String, MLtType>)>,
+    options: &Options,
+) -> String {
+    let mut output = "#include \"matlab_funcs.h\"\n\n".to_string();
+    if options.quaternions {
+        output += &quaternion_helpers(options);
+    }
+    if functions
+        .iter()
+        .any(|(function, _)| statements_contain(&function.body, &|e| matches!(e, MLtExpr::Skew(_))))
+    {
+        output += &skew_helper(options);
+    }
+    let is_random = |e: &MLtExpr| matches!(e, MLtExpr::Basic(MLtLValue::FunctionCall(f, _)) if f == "rand" || f == "randn");
+    if options.random_functions.is_none()
+        && functions
+            .iter()
+            .any(|(function, _)| statements_contain(&function.body, &is_random))
+    {
+        output += &random_helpers(options);
+    }
+    output += &forward_declarations(&functions, options);
+    for (idx, (function, mut ti_state)) in functions.into_iter().enumerate() {
+        if idx > 0 {
+            output += "\n";
+        }
+        let mut line_num = output.lines().count() as u32 + 1;
+        output += &generate_output_for_function(function, &mut ti_state, &mut line_num, options);
+    }
+    output
+}
+
+pub fn generate_output_file(
+    functions: Vec<(MLtFunction, HashMap<String, MLtType>)>,
+    options: &Options,
+) {
     let mut file = File::create("out.cpp").unwrap();
-    let mut line_num = 3;
-    let _ = file.write_all("#include \"matlab_funcs.h\"\n\n".as_bytes());
-    let _ =
-        file.write_all(generate_output_for_function(function, ti_state, &mut line_num).as_bytes());
+    let _ = file.write_all(generate_output(functions, options).as_bytes());
 }
@@ -1,28 +1,45 @@
 use chumsky::prelude::*;
+use common_subexpressions::eliminate_common_subexpressions;
+use constant_propagation::propagate_constants;
 use eigen_output::generate_output_file;
-use ml_parser::parser;
+use ml_parser::{insert_matrix_commas, parser};
+use options::{Options, ScalarType};
 use std::{
     collections::HashMap,
     env,
     fs::{self, File},
     io::Write,
+    path::Path,
 };
 use transform::transform_ast;
+use type_inference::{MLtType, register_quaternion_types};
 
+mod aliasing;
+mod builtins;
+mod common_subexpressions;
+mod constant_propagation;
 mod eigen_output;
 mod ml_parser;
+mod options;
+mod project;
+mod renaming;
 mod syntax;
 mod transform;
 mod type_inference;
 
+#[cfg(test)]
+mod tests;
+
 fn main() {
-    let src = fs::read_to_string(env::args().nth(1).expect("Expected file argument"))
-        .expect("Failed to read file");
+    let path = env::args()
+        .nth(1)
+        .expect("Expected file or directory argument");
 
     // type_inference state - stores function return types and matrix state
+    // the converted function's return type is inferred from the variable it returns, add a
+    // `_self` entry to set it when that isn't possible
     let mut ti_state = HashMap::from(
         [
-            ("_self", (13, 1)), // return type of the function being converted
             ("M_PI", (1, 1)),
             // used across several functions
             ("constantsASTRA.g", (1, 1)),
@@ -30,11 +47,6 @@ fn main() {
             ("constantsASTRA.Q", (18, 18)),
             ("constantsASTRA.R", (6, 6)),
             ("constantsASTRA.mag", (3, 1)),
-            // pablo's functions
-            ("StateTransitionMat", (9, 9)),
-            ("HamiltonianProd", (4, 4)),
-            ("zetaCross", (3, 3)),
-            ("quatRot", (3, 3)),
             // estimator types
             ("dT", (1, 1)),
             ("P", (9, 9)),
@@ -43,16 +55,56 @@ fn main() {
             ("x_est", (13, 1)),
             ("lastZ", (15, 1)),
         ]
-        .map(|(name, (rows, cols))| (name.to_string(), (rows, cols))),
+        .map(|(name, shape)| (name.to_string(), MLtType::real(shape))),
     );
+    // pablo's functions - return type and parameter types, calls are checked against the
+    // parameters and the functions are forward declared in the output
+    let function_types = [
+        ("StateTransitionMat", (9, 9), vec![(3, 1), (3, 1), (3, 3)]),
+        ("HamiltonianProd", (4, 4), vec![(4, 1)]),
+        ("zetaCross", (3, 3), vec![(3, 1)]),
+        ("quatRot", (3, 3), vec![(4, 1)]),
+    ];
+    for (name, shape, params) in function_types {
+        let params = params.into_iter().map(MLtType::real).collect();
+        ti_state.insert(name.to_string(), MLtType::real(shape).function(params));
+    }
+    // sizes that aren't fixed are given with `Dim::parse` - template parameters of the generated
+    // function like "N" or "N + 3", or "Dynamic" for sizes only known at runtime, so a vector of N
+    // is `MLtType::new(ElemType::Real, (Dim::parse("N"), Dim::parse("1")))`
+
+    let options = Options {
+        scalar: ScalarType::Float,
+        keep_constexpr: false,
+        symmetric_variables: ["P", "P0", "constantsASTRA.Q", "constantsASTRA.R"]
+            .map(String::from)
+            .to_vec(),
+        symmetrize: false,
+        quaternions: false,
+        skew_functions: vec!["zetaCross".to_string()],
+        random_functions: None,
+    };
+    if options.quaternions {
+        register_quaternion_types(&mut ti_state);
+    }
+
+    // a directory is converted as a project, with parameter types inferred from the call sites
+    if Path::new(&path).is_dir() {
+        project::convert_project(&path, ti_state, &options);
+        return;
+    }
 
+    let src = fs::read_to_string(path).expect("Failed to read file");
+    let src = insert_matrix_commas(&src);
     let (ast, err) = parser().parse(src.trim()).into_output_errors();
     match ast {
         Some(ast) => {
             let mut file = File::create("out.dbg").unwrap();
             let _ = file.write_all(format!("{ast:#?}").as_bytes());
-            let ast = transform_ast(ast);
-            generate_output_file(ast, &mut ti_state);
+            let ast = transform_ast(ast, &options);
+            let ast = propagate_constants(ast, &options);
+            let ast = eliminate_common_subexpressions(ast);
+            generate_output_file(vec![(ast, ti_state)], &options);
         }
         None => {
             println!("Error while parsing. {:#?}", err);
@@ -16,6 +16,81 @@ fn sident<'src>() -> impl Parser<'src, &'src str, String> + Clone {
     ident().map(String::from)
 }
 
+// an annotated size - a literal, `:` or `Dynamic` for runtime sizes, or a sum of template
+// parameters and literals like `N+3`, which are written in capitals so `% @param v next value`
+// stays a comment
+fn annotation_dim<'src>() -> impl Parser<'src, &'src str, &'src str> + Clone {
+    let size_name = any().filter(|c: &char| c.is_ascii_uppercase()).then(
+        any()
+            .filter(|c: &char| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == '_')
+            .repeated(),
+    );
+    choice((
+        just(":"),
+        just("Dynamic"),
+        choice((text::int(10).ignored(), size_name.ignored()))
+            .separated_by(just("+"))
+            .at_least(1)
+            .to_slice(),
+    ))
+}
+
+// matlab separates the elements of a matrix row with whitespace as well as commas (`[0 -w(3) w(2)]`)
+// which isn't context free, so whitespace separators inside brackets are replaced with commas
+// before parsing - `a -b` is two elements, `a - b` and `a -  b` are a subtraction
+// this keeps the length of the source the same
+pub fn insert_matrix_commas(src: &str) -> String {
+    let chars: Vec<char> = src.chars().collect();
+    let mut out = chars.clone();
+    let mut delimiters = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let prev = if i > 0 { chars[i - 1] } else { '\n' };
+        match c {
+            // comments and strings can contain anything
+            '%' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '\'' if !(prev.is_alphanumeric() || "_)]'.".contains(prev)) => {
+                i += 1;
+                while i < chars.len() && chars[i] != '\'' && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '[' | '(' => delimiters.push(c),
+            ']' | ')' => {
+                delimiters.pop();
+            }
+            ' ' | '\t' if delimiters.last() == Some(&'[') => {
+                let start = i;
+                while i + 1 < chars.len() && (chars[i + 1] == ' ' || chars[i + 1] == '\t') {
+                    i += 1;
+                }
+                let before = if start > 0 { chars[start - 1] } else { '[' };
+                let next = chars.get(i + 1).copied().unwrap_or(']');
+                let after_next = chars.get(i + 2).copied().unwrap_or(' ');
+                let ends_element = !"[(,;+-*/\\^=<>&|~\n".contains(before);
+                let starts_element = match next {
+                    '+' | '-' => !after_next.is_whitespace() && after_next != '=',
+                    '~' => after_next != '=',
+                    '.' => !"*/\\^'".contains(after_next),
+                    _ => !"]),;*/\\^=<>&|'\r\n".contains(next),
+                };
+                if ends_element && starts_element {
+                    out[i] = ',';
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    out.into_iter().collect()
+}
+
 pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
     let mlt_range = int(10)
         .then_ignore(kw(":"))
@@ -33,7 +108,7 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
             .then(
                 mlt_range
                     .clone()
-                    .separated_by(just(" "))
+                    .separated_by(choice((kw(","), just(" ").ignored())))
                     .collect()
                     .delimited_by(kw("(["), kw("])")),
             )
@@ -73,8 +148,20 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
             .repeated()
             .at_least(1)
             .collect()
-            .map(|s: String| {
-                if s.contains(".") || s.contains("e") {
+            // `2i` or `2j`, but not the start of an identifier like `2if`
+            .then(
+                one_of("ij")
+                    .then_ignore(
+                        any()
+                            .filter(|c: &char| c.is_alphanumeric() || *c == '_')
+                            .not(),
+                    )
+                    .or_not(),
+            )
+            .map(|(s, imaginary): (String, _)| {
+                if imaginary.is_some() {
+                    MLtLValue::Imaginary(s)
+                } else if s.contains(".") || s.contains("e") {
                     MLtLValue::Float(s)
                 } else {
                     MLtLValue::Integer(s)
@@ -82,10 +169,18 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
             }),
         mlt_expr
             .clone()
+            .separated_by(kw(","))
+            .at_least(1)
+            .collect()
             .separated_by(kw(";"))
             .collect()
             .delimited_by(kw("["), kw("]"))
             .map(MLtLValue::InlineMatrix),
+        none_of("'\r\n")
+            .repeated()
+            .collect()
+            .delimited_by(just("'"), just("'"))
+            .map(MLtLValue::Char),
         mlt_matrix.map(MLtLValue::Matrix),
     )));
 
@@ -110,6 +205,10 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
                 .clone()
                 .then_ignore(kw("'"))
                 .map(|e| MLtExpr::Transposed(Box::new(e))),
+            negated_atom
+                .clone()
+                .then_ignore(kw(".'"))
+                .map(|e| MLtExpr::PlainTransposed(Box::new(e))),
             negated_atom,
         ));
 
@@ -167,12 +266,25 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
         .then(mlt_expr.clone())
         .then_ignore(kw_no_newline(";"));
 
+    let mlt_multi_assignment = sident()
+        .map(|name| MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)))
+        .separated_by(kw(","))
+        .at_least(2)
+        .collect()
+        .delimited_by(kw_no_newline("["), kw("]"))
+        .then_ignore(kw("="))
+        .then(mlt_expr.clone())
+        .then_ignore(kw_no_newline(";"));
+
     let mut mlt_statement = Recursive::declare();
 
     mlt_statement.define(choice((
-        mlt_assignment.map(|(lvalue, expr)| MLtStatement::Assignment(lvalue, expr)),
+        // newlines go first, otherwise the padding around the `[` of an inline matrix lvalue eats them
         kw_no_newline("\r\n").to(MLtStatement::NewLine),
         kw_no_newline("\n").to(MLtStatement::NewLine),
+        // `[a, b] = ...` would also parse as assigning to an inline matrix
+        mlt_multi_assignment.map(|(lvalues, expr)| MLtStatement::MultiAssignment(lvalues, expr)),
+        mlt_assignment.map(|(lvalue, expr)| MLtStatement::Assignment(lvalue, expr)),
         kw_no_newline("persistent")
             .ignore_then(none_of("\r\n").repeated().collect::<String>())
             .padded()
@@ -183,6 +295,45 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
             .then(mlt_statement.clone().repeated().collect())
             .then_ignore(kw_no_newline("end"))
             .map(|(cond, body)| MLtStatement::IfStatement(cond, body)),
+        // `%#type P 12x12 double` or `% @param z 15x1` - the element type is optional
+        choice((
+            just("%#type"),
+            just("%")
+                .then(inline_whitespace())
+                .then(just("@param"))
+                .to_slice(),
+        ))
+        .ignore_then(inline_whitespace().at_least(1))
+        .ignore_then(
+            sident()
+                .separated_by(just("."))
+                .at_least(1)
+                .collect::<Vec<_>>(),
+        )
+        .then_ignore(inline_whitespace().at_least(1))
+        .then(
+            annotation_dim()
+                .then_ignore(just("x"))
+                .then(annotation_dim())
+                .then_ignore(one_of(" \t\r\n").rewind().ignored().or(end())),
+        )
+        .then(
+            inline_whitespace()
+                .at_least(1)
+                .ignore_then(sident())
+                .or_not(),
+        )
+        // anything after the type describes the variable
+        .then_ignore(none_of("\r\n").repeated())
+        .map(
+            |((name, (rows, cols)), elem): ((Vec<String>, (&str, &str)), _)| {
+                MLtStatement::TypeAnnotation(
+                    name.join("."),
+                    (rows.to_string(), cols.to_string()),
+                    elem,
+                )
+            },
+        ),
         kw_no_newline("%")
             .repeated()
             .at_least(1)
@@ -208,7 +359,7 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
                 .delimited_by(kw("("), kw_no_newline(")")),
         );
 
-    let mlt_function = mlt_function_header
+    mlt_function_header
         .then(mlt_statement.repeated().collect())
         .then_ignore(kw("end"))
         .map(|(((return_obj, name), params), body)| MLtFunction {
@@ -216,7 +367,5 @@ pub fn parser<'src>() -> impl Parser<'src, &'src str, MLtFunction> {
             name,
             params,
             body,
-        });
-
-    return mlt_function;
+        })
 }
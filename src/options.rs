@@ -0,0 +1,34 @@
+// scalar type of the generated code - `Template` makes the function a template over `Scalar`
+#[allow(dead_code)] // only one is selected in main
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScalarType {
+    Float,
+    Double,
+    Template,
+}
+
+// conversion options, set in `src/main.rs`
+pub struct Options {
+    // float, double or templated output - picks the typedef suffix (`Vector3f`, `Vector3d`) and
+    // the suffix of float literals
+    pub scalar: ScalarType,
+    // keep scalar constants like `FILTER_MODE = 1;` as `constexpr` declarations, only folding the
+    // expressions they take part in, instead of substituting the literal at every use
+    pub keep_constexpr: bool,
+    // variables (and struct fields) that are known to be symmetric on entry, like covariances
+    // `A * P * A'` with a symmetric `P` is converted to a product with `P.selfadjointView()`
+    pub symmetric_variables: Vec<String>,
+    // average symmetric matrices with their transpose after each `A * P * A'` update, to stop
+    // rounding errors from making them asymmetric
+    pub symmetrize: bool,
+    // convert quatmultiply, quatconj, quat2rotm etc to `Eigen::Quaternion` operations instead of
+    // passing them through as unknown functions
+    pub quaternions: bool,
+    // functions that build the skew symmetric (cross product) matrix of a 3 vector, converted to a
+    // generated `skew(v)`, or to `v.cross(u)` when the result is multiplied by a vector
+    pub skew_functions: Vec<String>,
+    // (rand, randn) function templates called as `name<Type>(rows, cols)`, or `name<Type>()` for
+    // scalars, so the output can draw from the application's random number generator - `None`
+    // writes `matlabRand` / `matlabRandn` helpers using a `std::mt19937`
+    pub random_functions: Option<(String, String)>,
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use chumsky::prelude::*;
+
+use crate::common_subexpressions::{
+    eliminate_common_subexpressions, statement_expressions, visit_subexpressions,
+};
+use crate::constant_propagation::propagate_constants;
+use crate::eigen_output::generate_output_file;
+use crate::ml_parser::{insert_matrix_commas, parser};
+use crate::options::Options;
+use crate::renaming::rename_shape_changes;
+use crate::syntax::*;
+use crate::transform::transform_ast;
+use crate::type_inference::{
+    MLtType, declared_types, expr_type, return_type, signature_annotations,
+};
+
+// converts a directory of .m files together - parameter types come from the call sites in other
+// functions of the project, and the types of calls come from the bodies of the functions called
+
+// the type of each parameter of a function, and the function it was first called from
+type ParamTypes = HashMap<String, Vec<Option<(MLtType, String)>>>;
+
+// same passes as single file mode
+fn load_function(path: &Path, options: &Options) -> Option<MLtFunction> {
+    let src = fs::read_to_string(path).expect("Failed to read file");
+    let src = insert_matrix_commas(&src);
+    let (ast, err) = parser().parse(src.trim()).into_output_errors();
+    match ast {
+        Some(ast) => {
+            let ast = transform_ast(ast, options);
+            let ast = propagate_constants(ast, options);
+            Some(eliminate_common_subexpressions(ast))
+        }
+        None => {
+            println!("Error while parsing {}. {:#?}", path.display(), err);
+            None
+        }
+    }
+}
+
+// calls to functions in the project, with their arguments
+fn project_calls(expr: &MLtExpr, project: &HashSet<String>) -> Vec<(String, Vec<MLtExpr>)> {
+    let mut calls = vec![];
+    visit_subexpressions(expr, &mut |e| {
+        if let MLtExpr::Basic(MLtLValue::FunctionCall(name, params)) = e
+            && project.contains(name)
+        {
+            calls.push((name.clone(), params.clone()));
+        }
+    });
+    calls
+}
+
+// declares the variables of a function body the same way as the output, then records the argument
+// types of each call to a project function
+fn infer_statements(
+    statements: &[MLtStatement],
+    ti_state: &mut HashMap<String, MLtType>,
+    project: &HashSet<String>,
+    calls: &mut Vec<(String, Vec<MLtType>)>,
+) {
+    let declared = declared_types(statements, ti_state, 0, true);
+    ti_state.extend(declared);
+    record_calls(statements, ti_state, project, calls);
+}
+
+// without warnings, since the output infers the types again
+fn record_calls(
+    statements: &[MLtStatement],
+    ti_state: &mut HashMap<String, MLtType>,
+    project: &HashSet<String>,
+    calls: &mut Vec<(String, Vec<MLtType>)>,
+) {
+    let mut line_num = 0;
+    for statement in statements {
+        for expr in statement_expressions(statement) {
+            for (callee, params) in project_calls(expr, project) {
+                let arg_types = params
+                    .iter()
+                    .map(|p| expr_type(p, ti_state, &mut line_num, true))
+                    .collect();
+                calls.push((callee, arg_types));
+            }
+        }
+        if let MLtStatement::IfStatement(_, body) | MLtStatement::PersistentInit(_, body) =
+            statement
+        {
+            record_calls(body, ti_state, project, calls);
+        }
+    }
+}
+
+// the types a function starts with - the shared types, the return types of the project functions
+// and whatever is known about its parameters
+fn function_state(
+    function: &MLtFunction,
+    base: &HashMap<String, MLtType>,
+    param_types: &ParamTypes,
+    return_types: &HashMap<String, MLtType>,
+) -> HashMap<String, MLtType> {
+    let mut ti_state = base.clone();
+    ti_state.extend(return_types.iter().map(|(k, v)| (k.clone(), v.clone())));
+    for (param, t) in function.params.iter().zip(&param_types[&function.name]) {
+        if let Some((t, _)) = t {
+            ti_state.insert(
+                param.strip_prefix("&").unwrap_or(param).to_string(),
+                t.clone(),
+            );
+        }
+    }
+    // a function returning a parameter that nothing calls it with
+    if return_type(function, &ti_state).is_none() {
+        ti_state.insert("_self".to_string(), MLtType::dynamic());
+    }
+    ti_state
+}
+
+// callees are converted before their callers
+fn call_order(
+    name: &str,
+    call_graph: &HashMap<String, BTreeSet<String>>,
+    visited: &mut HashSet<String>,
+    order: &mut Vec<String>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+    for callee in &call_graph[name] {
+        call_order(callee, call_graph, visited, order);
+    }
+    order.push(name.to_string());
+}
+
+pub fn convert_project(dir: &str, base: HashMap<String, MLtType>, options: &Options) {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .expect("Failed to read directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "m"))
+        .collect();
+    paths.sort();
+    let functions = paths
+        .iter()
+        .filter_map(|path| load_function(path, options))
+        .collect();
+    generate_output_file(infer_project(functions, &base), options);
+}
+
+// the functions in call order (callees first), each with the types it starts with
+pub fn infer_project(
+    functions: Vec<MLtFunction>,
+    base: &HashMap<String, MLtType>,
+) -> Vec<(MLtFunction, HashMap<String, MLtType>)> {
+    let functions: HashMap<String, MLtFunction> = functions
+        .into_iter()
+        .map(|function| (function.name.clone(), function))
+        .collect();
+    let project: HashSet<String> = functions.keys().cloned().collect();
+
+    let mut call_graph = HashMap::new();
+    for (name, function) in &functions {
+        let mut callees = BTreeSet::new();
+        let mut calls = vec![];
+        infer_statements(&function.body, &mut base.clone(), &project, &mut calls);
+        callees.extend(calls.into_iter().map(|(callee, _)| callee));
+        call_graph.insert(name.clone(), callees);
+    }
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    let mut names: Vec<&String> = functions.keys().collect();
+    names.sort();
+    for name in names {
+        call_order(name, &call_graph, &mut visited, &mut order);
+    }
+
+    // parameters that are given a type directly keep it, annotations first
+    let mut param_types: ParamTypes = functions
+        .values()
+        .map(|function| {
+            let annotations: HashMap<String, MLtType> =
+                signature_annotations(function).into_iter().collect();
+            let types = function
+                .params
+                .iter()
+                .map(|p| {
+                    let p = p.strip_prefix("&").unwrap_or(p);
+                    match annotations.get(p) {
+                        Some(t) => Some((t.clone(), "its annotation".to_string())),
+                        None => base.get(p).map(|t| (t.clone(), "ti_state".to_string())),
+                    }
+                })
+                .collect();
+            (function.name.clone(), types)
+        })
+        .collect();
+    let mut return_types: HashMap<String, MLtType> = HashMap::new();
+    let mut conflicts = BTreeSet::new();
+
+    // each pass can only push types one call further, and types only go from unknown or dynamic
+    // to known, so this settles
+    loop {
+        let mut changed = false;
+        for name in &order {
+            let mut ti_state = function_state(&functions[name], base, &param_types, &return_types);
+            // the returned variable may be renamed if it changes shape
+            let function = rename_shape_changes(functions[name].clone(), &ti_state, true);
+            let mut calls = vec![];
+            infer_statements(&function.body, &mut ti_state, &project, &mut calls);
+
+            for (callee, arg_types) in calls {
+                let callee_params = &functions[&callee].params;
+                let known = param_types.get_mut(&callee).unwrap();
+                for ((param, known), t) in callee_params.iter().zip(known.iter_mut()).zip(arg_types)
+                {
+                    match known {
+                        None => {
+                            *known = Some((t, name.clone()));
+                            changed = true;
+                        }
+                        // a caller that was inferred before its own parameters were known
+                        Some((known_type, first_caller))
+                            if known_type.is_dynamic() && !t.is_dynamic() =>
+                        {
+                            *known_type = t.with_elem(known_type.elem.max(t.elem));
+                            *first_caller = name.clone();
+                            changed = true;
+                        }
+                        Some((known_type, first_caller)) if known_type.conflicts(&t) => {
+                            conflicts.insert(format!(
+                                "Project type warning: {} is called with {} as {} by {} from {}, but as {} by {} from {}.",
+                                callee, param, t.rows, t.cols, name, known_type.rows, known_type.cols, first_caller
+                            ));
+                        }
+                        // an int in one call and a float in another is a float
+                        Some((known_type, _)) if known_type.elem < t.elem => {
+                            *known_type = known_type.with_elem(t.elem);
+                            changed = true;
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+
+            // a dynamic return type is from a pass before the parameters were known
+            if let Some(t) = ti_state.get(&function.return_obj)
+                && return_types.get(name) != Some(t)
+                && !(t.is_dynamic() && return_types.get(name).is_some_and(|r| !r.is_dynamic()))
+            {
+                return_types.insert(name.clone(), t.clone());
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for conflict in conflicts {
+        println!("{}", conflict);
+    }
+
+    order
+        .iter()
+        .map(|name| {
+            let function = functions[name].clone();
+            let ti_state = function_state(&function, base, &param_types, &return_types);
+            (function, ti_state)
+        })
+        .collect()
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use crate::syntax::*;
+use crate::type_inference::{MLtType, assignment_type, declared_types};
+
+// C++ variables can't change type, so when a local variable is assigned a different shape it is
+// renamed from there on, `H` becomes `H_2` - only assignments outside of if statements can be
+// renamed, eigen_output reports the rest as errors
+
+fn rename_access(access: MLtMatrixAccess, old: &str, new: &str) -> MLtMatrixAccess {
+    let rename = |name: String| match name == old {
+        true => new.to_string(),
+        false => name,
+    };
+    match access {
+        MLtMatrixAccess::Matrix(name) => MLtMatrixAccess::Matrix(rename(name)),
+        MLtMatrixAccess::MatrixIndex(name, idx) => MLtMatrixAccess::MatrixIndex(rename(name), idx),
+        MLtMatrixAccess::MatrixSegment(name, range) => {
+            MLtMatrixAccess::MatrixSegment(rename(name), range)
+        }
+        MLtMatrixAccess::MatrixMultiSegment(name, ranges) => {
+            MLtMatrixAccess::MatrixMultiSegment(rename(name), ranges)
+        }
+        MLtMatrixAccess::MatrixBlock(name, rows, cols) => {
+            MLtMatrixAccess::MatrixBlock(rename(name), rows, cols)
+        }
+    }
+}
+
+fn rename_lvalue(lvalue: MLtLValue, old: &str, new: &str) -> MLtLValue {
+    match lvalue {
+        MLtLValue::Matrix(access) => MLtLValue::Matrix(rename_access(access, old, new)),
+        MLtLValue::InlineMatrix(rows) => MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(|e| rename_expr(e, old, new)).collect())
+                .collect(),
+        ),
+        // `x(1, 2)` is parsed as a call
+        MLtLValue::FunctionCall(name, args) => MLtLValue::FunctionCall(
+            match name == old {
+                true => new.to_string(),
+                false => name,
+            },
+            args.into_iter().map(|e| rename_expr(e, old, new)).collect(),
+        ),
+        lvalue => lvalue,
+    }
+}
+
+fn rename_expr(expr: MLtExpr, old: &str, new: &str) -> MLtExpr {
+    let rename_boxed = |e: Box<MLtExpr>| Box::new(rename_expr(*e, old, new));
+    match expr {
+        MLtExpr::Basic(lvalue) => MLtExpr::Basic(rename_lvalue(lvalue, old, new)),
+        MLtExpr::Negation(e) => MLtExpr::Negation(rename_boxed(e)),
+        MLtExpr::Transposed(e) => MLtExpr::Transposed(rename_boxed(e)),
+        MLtExpr::PlainTransposed(e) => MLtExpr::PlainTransposed(rename_boxed(e)),
+        MLtExpr::Parenthesized(e) => MLtExpr::Parenthesized(rename_boxed(e)),
+        MLtExpr::Normalized(e) => MLtExpr::Normalized(rename_boxed(e)),
+        MLtExpr::SquaredNorm(e) => MLtExpr::SquaredNorm(rename_boxed(e)),
+        MLtExpr::Skew(e) => MLtExpr::Skew(rename_boxed(e)),
+        MLtExpr::BinOp(left, op, right) => {
+            MLtExpr::BinOp(rename_boxed(left), op, rename_boxed(right))
+        }
+        MLtExpr::SymmetricProduct(left, middle) => {
+            MLtExpr::SymmetricProduct(rename_boxed(left), rename_boxed(middle))
+        }
+    }
+}
+
+fn rename_statement(statement: MLtStatement, old: &str, new: &str) -> MLtStatement {
+    let rename = |name: String| match name == old {
+        true => new.to_string(),
+        false => name,
+    };
+    match statement {
+        MLtStatement::Assignment(lvalue, expr) => {
+            MLtStatement::Assignment(rename_lvalue(lvalue, old, new), rename_expr(expr, old, new))
+        }
+        MLtStatement::MultiAssignment(lvalues, expr) => MLtStatement::MultiAssignment(
+            lvalues
+                .into_iter()
+                .map(|lvalue| rename_lvalue(lvalue, old, new))
+                .collect(),
+            rename_expr(expr, old, new),
+        ),
+        MLtStatement::IfStatement(cond, body) => MLtStatement::IfStatement(
+            rename_expr(cond, old, new),
+            body.into_iter()
+                .map(|s| rename_statement(s, old, new))
+                .collect(),
+        ),
+        MLtStatement::PersistentInit(name, body) => MLtStatement::PersistentInit(
+            name,
+            body.into_iter()
+                .map(|s| rename_statement(s, old, new))
+                .collect(),
+        ),
+        MLtStatement::Normalization(lvalue) => {
+            MLtStatement::Normalization(rename_lvalue(lvalue, old, new))
+        }
+        MLtStatement::Constant(name, expr) => {
+            MLtStatement::Constant(name, rename_expr(expr, old, new))
+        }
+        MLtStatement::Temporary(name, expr) => {
+            MLtStatement::Temporary(name, rename_expr(expr, old, new))
+        }
+        MLtStatement::Symmetrize(name) => MLtStatement::Symmetrize(rename(name)),
+        MLtStatement::TypeAnnotation(name, dims, elem) => {
+            MLtStatement::TypeAnnotation(rename(name), dims, elem)
+        }
+        statement => statement,
+    }
+}
+
+// renames the variables in the function body that are assigned a different shape
+pub fn rename_shape_changes(
+    mut function: MLtFunction,
+    ti_state: &HashMap<String, MLtType>,
+    quiet: bool,
+) -> MLtFunction {
+    // parameters passed by reference are written back to the caller, so they keep their name
+    let renamable = |name: &str| {
+        !function
+            .params
+            .iter()
+            .any(|p| p.strip_prefix("&") == Some(name))
+    };
+    let mut taken = ti_state.clone();
+    taken.extend(declared_types(&function.body, ti_state, 0, true));
+
+    let mut line_num = 0;
+    let mut renames = vec![];
+    for idx in 0..function.body.len() {
+        // the variables declared before this statement, the same way as the output
+        let mut state = ti_state.clone();
+        state.extend(declared_types(&function.body[..idx], ti_state, 0, true));
+        if let MLtStatement::Assignment(MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)), expr) =
+            &function.body[idx]
+            && renamable(name)
+            && let Some(declared) = state.get(name).cloned()
+        {
+            let assigned = assignment_type(expr, &mut state, &mut line_num, true);
+            if declared.conflicts(&assigned) {
+                let old = name.clone();
+                let new = (2..)
+                    .map(|n| format!("{}_{}", old, n))
+                    .find(|new| !taken.contains_key(new))
+                    .unwrap();
+                taken.insert(new.clone(), assigned.clone());
+                // the right side still reads the old variable
+                let rest = function.body.split_off(idx + 1);
+                if let Some(MLtStatement::Assignment(lvalue, _)) = function.body.last_mut() {
+                    *lvalue = MLtLValue::Matrix(MLtMatrixAccess::Matrix(new.clone()));
+                }
+                function
+                    .body
+                    .extend(rest.into_iter().map(|s| rename_statement(s, &old, &new)));
+                if function.return_obj == old {
+                    function.return_obj = new.clone();
+                }
+                renames.push((old, declared, new, assigned));
+            }
+        }
+    }
+
+    for (old, declared, new, assigned) in renames {
+        if !quiet {
+            println!(
+                "Shape change warning: {} is {} by {} and is then assigned a {} by {}, so it is renamed to {} from there on.",
+                old, declared.rows, declared.cols, assigned.rows, assigned.cols, new
+            );
+        }
+    }
+    function
+}
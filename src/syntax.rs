@@ -1,4 +1,4 @@
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MLtFunction {
     pub return_obj: String, // TODO - multiple returns?
     pub name: String,
@@ -6,18 +6,24 @@ pub struct MLtFunction {
     pub body: Vec<MLtStatement>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MLtStatement {
     Assignment(MLtLValue, MLtExpr),
-    Persistent(Vec<String>),                 // list of persistent variables
-    IfStatement(MLtExpr, Vec<MLtStatement>), // condition, list of statements
+    MultiAssignment(Vec<MLtLValue>, MLtExpr), // [V, D] = eig(A)
+    Persistent(Vec<String>),                  // list of persistent variables
+    IfStatement(MLtExpr, Vec<MLtStatement>),  // condition, list of statements
     Comment(String),
     Error(String),
     NewLine,
-    Normalization(String), // not parsed in, detected in transform pass
+    Normalization(MLtLValue), // x = x / norm(x), not parsed in, detected in transform pass
+    PersistentInit(String, Vec<MLtStatement>), // `if isempty(persistent)`, detected in transform pass
+    Constant(String, MLtExpr),                 // constexpr, detected in constant propagation pass
+    Temporary(String, MLtExpr), // hoisted subexpression, created in transform and cse passes
+    Symmetrize(String), // P = (P + P') / 2 after a symmetric update, created in transform pass
+    TypeAnnotation(String, (String, String), Option<String>), // `%#type P 12x12 double` - name, (rows, cols), element type
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MLtMatrixAccess {
     Matrix(String),                            // z
     MatrixIndex(String, u32), // z(1) - this is impossible to tell from a function call during parsing so we catch it as a transform
@@ -26,32 +32,39 @@ pub enum MLtMatrixAccess {
     MatrixBlock(String, MLtRange, MLtRange), // z(1:3, 4:5)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MLtLValue {
-    Integer(String), // 1 - we keep this as a string because we don't need to edit it
-    Float(String),   // 0.5 - we keep this as a string because we don't need to edit it
+    Integer(String),   // 1 - we keep this as a string because we don't need to edit it
+    Float(String),     // 0.5 - we keep this as a string because we don't need to edit it
+    Imaginary(String), // 2i - the imaginary part, kept as a string like the other literals
     Matrix(MLtMatrixAccess), // `z`
     StructMatrix(String, MLtMatrixAccess), // constants.z
-    InlineMatrix(Vec<MLtExpr>), // [0; 1; z]
+    InlineMatrix(Vec<Vec<MLtExpr>>), // [0; 1; z] or [a, b; c, d] - list of rows
+    Char(String),      // 'lower' - only used as an option to builtins
     FunctionCall(String, Vec<MLtExpr>), // telling these from single access is impossible in matlab, list of params
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MLtExpr {
     Basic(MLtLValue), // lvalue or lvalue'
     Negation(Box<MLtExpr>),
-    Transposed(Box<MLtExpr>), // transposed will be parenthesized or lvalue
+    Transposed(Box<MLtExpr>), // x' - conjugate transpose, transposed will be parenthesized or lvalue
+    PlainTransposed(Box<MLtExpr>), // x.' - transpose without conjugating
     Parenthesized(Box<MLtExpr>),
     BinOp(Box<MLtExpr>, MLtBinOp, Box<MLtExpr>), // "lvalue + lvalue", or sub, mul, div
+    SymmetricProduct(Box<MLtExpr>, Box<MLtExpr>), // A * B * A' with B symmetric, detected in transform pass
+    Normalized(Box<MLtExpr>),                     // x / norm(x), detected in transform pass
+    SquaredNorm(Box<MLtExpr>),                    // norm(x)^2, detected in transform pass
+    Skew(Box<MLtExpr>), // [0 -w(3) w(2); w(3) 0 -w(1); -w(2) w(1) 0] or a skew helper, detected in transform pass
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MLtRange {
     pub start: u32,
     pub end: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MLtBinOp {
     Add,
     Sub,
@@ -0,0 +1,871 @@
+use std::collections::HashMap;
+
+use chumsky::prelude::*;
+
+use crate::common_subexpressions::eliminate_common_subexpressions;
+use crate::constant_propagation::propagate_constants;
+use crate::eigen_output::generate_output;
+use crate::ml_parser::{insert_matrix_commas, parser};
+use crate::options::{Options, ScalarType};
+use crate::project::infer_project;
+use crate::syntax::MLtFunction;
+use crate::transform::transform_ast;
+use crate::type_inference::{Dim, ElemType, MLtType, register_quaternion_types};
+
+// input -> output checks of the converter, each source is run through the same passes as main
+
+fn options() -> Options {
+    Options {
+        scalar: ScalarType::Float,
+        keep_constexpr: false,
+        symmetric_variables: vec![],
+        symmetrize: false,
+        quaternions: false,
+        skew_functions: vec![],
+        random_functions: None,
+    }
+}
+
+fn types(shapes: &[(&str, (u32, u32))]) -> HashMap<String, MLtType> {
+    shapes
+        .iter()
+        .map(|(name, shape)| (name.to_string(), MLtType::real(*shape)))
+        .collect()
+}
+
+fn load(src: &str, options: &Options) -> MLtFunction {
+    let src = insert_matrix_commas(src);
+    let ast = parser()
+        .parse(src.trim())
+        .into_result()
+        .expect("test source should parse");
+    let ast = transform_ast(ast, options);
+    let ast = propagate_constants(ast, options);
+    eliminate_common_subexpressions(ast)
+}
+
+fn convert_with(src: &str, ti_state: HashMap<String, MLtType>, options: &Options) -> String {
+    generate_output(vec![(load(src, options), ti_state)], options)
+}
+
+// the sources are converted together, like the files of a project directory
+fn convert_project(srcs: &[&str], shapes: &[(&str, (u32, u32))]) -> String {
+    let functions = srcs.iter().map(|src| load(src, &options())).collect();
+    generate_output(infer_project(functions, &types(shapes)), &options())
+}
+
+fn convert(src: &str, shapes: &[(&str, (u32, u32))]) -> String {
+    convert_with(src, types(shapes), &options())
+}
+
+#[track_caller]
+fn assert_contains(output: &str, expected: &str) {
+    assert!(
+        output.contains(expected),
+        "expected `{}` in the output:\n{}",
+        expected,
+        output
+    );
+}
+
+#[test]
+fn persistent_isempty_becomes_initialization_flag() {
+    let output = convert(
+        "function x = f(x)\npersistent P\nif isempty(P)\nP = eye(2);\nend\nx = P * x;\nend",
+        &[("_self", (2, 1)), ("x", (2, 1)), ("P", (2, 2))],
+    );
+    assert_contains(
+        &output,
+        "Vector2f f(Vector2f x, Matrix2_2f &P, bool &P_initialized) {",
+    );
+    assert_contains(
+        &output,
+        "if (!P_initialized) {\nP = Matrix2_2f::Identity();\nP_initialized = true;\n}",
+    );
+    assert!(!output.contains("isempty"));
+    assert!(!output.contains("static"));
+}
+
+#[test]
+fn reductions_follow_matlab_dimensions() {
+    let output = convert(
+        "function s = f(A, v)\na = sum(v);\nb = sum(A);\nc = mean(A, 2);\nt = trace(A) + det(A);\nm = max(v);\ns = a + t + m;\nend",
+        &[("_self", (1, 1)), ("A", (3, 3)), ("v", (3, 1))],
+    );
+    assert_contains(&output, "float a = v.sum();");
+    assert_contains(&output, "Matrix1_3f b = A.colwise().sum();");
+    assert_contains(&output, "Vector3f c = A.rowwise().mean();");
+    assert_contains(&output, "float t = A.trace() + A.determinant();");
+    assert_contains(&output, "float m = v.maxCoeff();");
+}
+
+#[test]
+fn reductions_along_missing_dimensions_keep_the_matrix() {
+    let output = convert(
+        "function B = f(A, d)\nB = sum(A, 3);\nC = any(A, 3);\nD = sum(A, d);\nend",
+        &[("_self", (3, 3)), ("A", (3, 3)), ("d", (1, 1))],
+    );
+    assert_contains(&output, "B = A;");
+    assert_contains(&output, "Eigen::Array<bool, 3, 3> C = (A.array() != 0);");
+    // a runtime dim falls back to matlab's default
+    assert_contains(&output, "Matrix1_3f D = A.colwise().sum();");
+}
+
+#[test]
+fn elementwise_builtins_on_scalars_and_matrices() {
+    let output = convert(
+        "function y = f(v, w, a, b)\nc = sqrt(a + b);\nr = mod(a, b);\nm = mod(v, w);\nq = abs(v - w);\ny = atan2(v, w) + m + q * (c + r);\nend",
+        &[
+            ("_self", (3, 1)),
+            ("v", (3, 1)),
+            ("w", (3, 1)),
+            ("a", (1, 1)),
+            ("b", (1, 1)),
+        ],
+    );
+    assert_contains(&output, "float c = std::sqrt(a + b);");
+    assert_contains(&output, "Vector3f q = (v - w).cwiseAbs();");
+    assert_contains(
+        &output,
+        "v.binaryExpr(w, [](auto a, auto b) { return std::atan2(a, b); })",
+    );
+    // matlab's mod(a, 0) is a, where the plain formula divides by zero
+    assert_contains(
+        &output,
+        "float r = (b == 0 ? a : a - std::floor(a / b) * b);",
+    );
+    assert_contains(
+        &output,
+        "Vector3f m = v.binaryExpr(w, [](auto a, auto b) { return b == 0 ? a : a - std::floor(a / b) * b; });",
+    );
+}
+
+#[test]
+fn matrix_and_elementwise_powers() {
+    let output = convert(
+        "function y = f(A, v, a)\nB = A^2;\nC = A^-1;\nw = v.^3;\nu = v.^a;\ns = a^0.5;\ny = B * v + C * v + w + u * s;\nend",
+        &[
+            ("_self", (3, 1)),
+            ("A", (3, 3)),
+            ("v", (3, 1)),
+            ("a", (1, 1)),
+        ],
+    );
+    assert_contains(&output, "Matrix3_3f B = (A * A);");
+    assert_contains(&output, "Matrix3_3f C = A.inverse();");
+    assert_contains(&output, "Vector3f w = v.array().pow(3).matrix();");
+    assert_contains(&output, "Vector3f u = v.array().pow(a).matrix();");
+    assert_contains(&output, "float s = std::pow(a, 0.5f);");
+}
+
+#[test]
+fn linear_algebra_builtins() {
+    let output = convert(
+        "function y = f(A, v)\nB = inv(A);\nL = chol(A, 'lower');\nR = qr(A);\n[Q, R2] = qr(A);\ny = B * v + L * v + R * v + Q * R2 * v;\nend",
+        &[("_self", (3, 1)), ("A", (3, 3)), ("v", (3, 1))],
+    );
+    assert_contains(&output, "Matrix3_3f B = A.inverse();");
+    assert_contains(&output, "Matrix3_3f L = A.llt().matrixL();");
+    assert_contains(
+        &output,
+        "Matrix3_3f R = A.householderQr().matrixQR().triangularView<Eigen::Upper>();",
+    );
+    assert_contains(&output, "Eigen::HouseholderQR<Matrix3_3f> Q_R2_qr(A);");
+    assert_contains(&output, "Matrix3_3f Q = Q_R2_qr.householderQ();");
+}
+
+#[test]
+fn eig_uses_the_general_solver_and_extra_outputs_are_errors() {
+    let output = convert(
+        "function y = f(A, v)\n[V, D] = eig(A);\nl = eig(A);\n[a, b, c, d] = svd(A);\ny = V * D * v + l;\nend",
+        &[("_self", (3, 1)), ("A", (3, 3)), ("v", (3, 1))],
+    );
+    assert_contains(&output, "Eigen::EigenSolver<Matrix3_3f> V_D_eig(A);");
+    assert_contains(&output, "Matrix3_3f V = V_D_eig.eigenvectors().real();");
+    assert_contains(
+        &output,
+        "Matrix3_3f D = V_D_eig.eigenvalues().real().asDiagonal();",
+    );
+    assert_contains(&output, "Vector3f l = A.eigenvalues().real();");
+    assert_contains(&output, "// [...] = svd(...); // could not be converted");
+}
+
+#[test]
+fn constants_fold_into_finite_literals_and_logical_values() {
+    let output = convert(
+        "function y = f(a)\nFLAG = 1;\nbig = 10^400;\nb = FLAG && a;\nc = FLAG && a > 2;\nif FLAG && a\ny = a + big + b + c;\nend\nend",
+        &[("_self", (1, 1)), ("a", (1, 1)), ("y", (1, 1))],
+    );
+    // 10^400 is inf as a double, which has no literal
+    assert_contains(&output, "float big = std::pow(10, 400);");
+    // `1 && a` is 0 or 1, not a
+    assert_contains(&output, "b = (a != 0);");
+    assert_contains(&output, "c = a > 2;");
+    assert_contains(&output, "if ((a != 0)) {");
+    assert!(!output.contains("inf") && !output.contains("NaN"));
+}
+
+#[test]
+fn repeated_products_are_hoisted() {
+    let output = convert(
+        "function y = f(H, P, v)\nS = H * P * H' + eye(2);\nK = P * H' / (H * P * H');\ny = S * v + H * K * v;\nend",
+        &[
+            ("_self", (2, 1)),
+            ("H", (2, 3)),
+            ("P", (3, 3)),
+            ("v", (2, 1)),
+        ],
+    );
+    assert_contains(&output, "Matrix2_2f cse_0 = H * P * H.transpose();");
+    assert_contains(&output, "Matrix2_2f S = cse_0 + Matrix2_2f::Identity();");
+    assert_contains(
+        &output,
+        "Matrix3_2f K = P * H.transpose() * (cse_0).inverse();",
+    );
+}
+
+#[test]
+fn symmetric_products_use_selfadjoint_views() {
+    let options = Options {
+        symmetric_variables: vec!["P".to_string()],
+        symmetrize: true,
+        ..options()
+    };
+    let output = convert_with(
+        "function y = f(A, P, v, x)\nP = A * P * A' + diag(v(1:3));\nR = diag([1 2 3]);\nQ = diag([2 * ones(2, 1); x(1)]);\nD = diag(x);\nM = A * R * A' + A * Q * A' + A * D * A';\ny = P * v + M * v;\nend",
+        types(&[
+            ("_self", (3, 1)),
+            ("A", (3, 3)),
+            ("P", (3, 3)),
+            ("v", (3, 1)),
+            ("x", (3, 1)),
+        ]),
+        &options,
+    );
+    assert_contains(
+        &output,
+        "P = A * P.selfadjointView<Eigen::Lower>() * A.transpose() + v.segment<3>(0).asDiagonal();\nP = (0.5f * (P + P.transpose())).eval();\n",
+    );
+    assert_contains(
+        &output,
+        "A * R.selfadjointView<Eigen::Lower>() * A.transpose()",
+    );
+    assert_contains(
+        &output,
+        "A * Q.selfadjointView<Eigen::Lower>() * A.transpose()",
+    );
+    // x could be a matrix, whose diagonal is a vector
+    assert_contains(&output, "+ A * D * A.transpose();");
+}
+
+#[test]
+fn sandwich_factors_are_computed_once() {
+    let options = Options {
+        symmetric_variables: vec!["P".to_string(), "R".to_string()],
+        ..options()
+    };
+    let output = convert_with(
+        "function P = f(P, K, H, R)\nP = (eye(3) - K * H) * P * (eye(3) - K * H)' + K * R * K';\nend",
+        types(&[
+            ("_self", (3, 3)),
+            ("P", (3, 3)),
+            ("K", (3, 2)),
+            ("H", (2, 3)),
+            ("R", (2, 2)),
+        ]),
+        &options,
+    );
+    assert_contains(
+        &output,
+        "Matrix3_3f sandwich_0 = Matrix3_3f::Identity() - K * H;\nP = sandwich_0 * P.selfadjointView<Eigen::Lower>() * sandwich_0.transpose() + K * R.selfadjointView<Eigen::Lower>() * K.transpose();",
+    );
+}
+
+#[test]
+fn partial_writes_to_struct_fields_clear_symmetry() {
+    let options = Options {
+        symmetric_variables: vec!["s.P".to_string(), "s.Q".to_string(), "s.R".to_string()],
+        ..options()
+    };
+    let output = convert_with(
+        "function y = f(s, A, v)\ns.P(1:2) = v(1:2);\ns.Q(1:2, 2:3) = ones(2, 2);\ns.R(1:2, 1:2) = eye(2);\ny = A * s.P * A' * v + A * s.Q * A' * v + A * s.R * A' * v;\nend",
+        types(&[
+            ("_self", (3, 1)),
+            ("s.P", (3, 3)),
+            ("s.Q", (3, 3)),
+            ("s.R", (3, 3)),
+            ("A", (3, 3)),
+            ("v", (3, 1)),
+        ]),
+        &options,
+    );
+    assert_contains(&output, "A * s.P * A.transpose() * v");
+    assert_contains(&output, "A * s.Q * A.transpose() * v");
+    // a symmetric block on the diagonal keeps the matrix symmetric
+    assert_contains(
+        &output,
+        "A * s.R.selfadjointView<Eigen::Lower>() * A.transpose() * v",
+    );
+}
+
+#[test]
+fn eig_of_symmetric_matrices_uses_the_selfadjoint_solver() {
+    let options = Options {
+        symmetric_variables: vec!["P".to_string()],
+        ..options()
+    };
+    let output = convert_with(
+        "function y = f(P, A, v)\n[V, D] = eig(P);\nl = eig(P);\nm = eig(A);\ny = V * D * v + l + m;\nend",
+        types(&[
+            ("_self", (3, 1)),
+            ("P", (3, 3)),
+            ("A", (3, 3)),
+            ("v", (3, 1)),
+        ]),
+        &options,
+    );
+    assert_contains(
+        &output,
+        "Eigen::SelfAdjointEigenSolver<Matrix3_3f> V_D_selfadjoint_eig(P);",
+    );
+    assert_contains(
+        &output,
+        "Matrix3_3f V = V_D_selfadjoint_eig.eigenvectors();",
+    );
+    assert_contains(
+        &output,
+        "Vector3f l = P.selfadjointView<Eigen::Lower>().eigenvalues();",
+    );
+    assert_contains(&output, "Vector3f m = A.eigenvalues().real();");
+}
+
+#[test]
+fn updates_are_compound_and_products_noalias() {
+    let output = convert(
+        "function y = f(x, dx, A, L, H, P)\nx = x + dx;\nP = P - L * H * P;\nG = A;\nG = A * P;\nILH = A;\nILH = (eye(3) - A * L * H);\nB = A;\nB = (P * A);\nx = x + A * dx;\ny = x + G * ILH * B * dx;\nend",
+        &[
+            ("_self", (3, 1)),
+            ("x", (3, 1)),
+            ("dx", (3, 1)),
+            ("A", (3, 3)),
+            ("L", (3, 2)),
+            ("H", (2, 3)),
+            ("P", (3, 3)),
+        ],
+    );
+    assert_contains(&output, "x += dx;");
+    // the product is evaluated into a temporary before P is updated
+    assert_contains(&output, "P -= L * H * P;");
+    assert_contains(&output, "G.noalias() = A * P;");
+    assert_contains(&output, "B.noalias() = (P * A);");
+    assert_contains(&output, "x.noalias() += A * dx;");
+    // a sum isn't a product, even if it contains one
+    assert_contains(&output, "ILH = (Matrix3_3f::Identity() - A * L * H);");
+}
+
+#[test]
+fn quaternion_builtins_convert_the_layout() {
+    let options = Options {
+        quaternions: true,
+        ..options()
+    };
+    let mut ti_state = types(&[("_self", (1, 4)), ("q", (4, 1)), ("p", (4, 1))]);
+    register_quaternion_types(&mut ti_state);
+    let output = convert_with(
+        "function y = f(q, p)\nr = quatmultiply(q, p);\nc = quatconj(q);\nn = quatnormalize(r);\ni = quatinv(p);\nR = quat2rotm(n);\nq2 = rotm2quat(R);\ny = c + i + q2;\nend",
+        ti_state,
+        &options,
+    );
+    // matlab stores the scalar first, eigen's coefficients store it last
+    assert_contains(
+        &output,
+        "return Eigen::Quaternionf(q(0), q(1), q(2), q(3));",
+    );
+    assert_contains(
+        &output,
+        "Matrix1_4f r = quatToMatlab((quatFromMatlab(q) * quatFromMatlab(p)));",
+    );
+    assert_contains(&output, "quatToMatlab(quatFromMatlab(q).conjugate());");
+    assert_contains(&output, "quatToMatlab(quatFromMatlab(r).normalized());");
+    assert_contains(&output, "quatToMatlab(quatFromMatlab(p).inverse());");
+    assert_contains(
+        &output,
+        "Matrix3_3f R = quatFromMatlab(n).toRotationMatrix();",
+    );
+    assert_contains(
+        &output,
+        "Matrix1_4f q2 = quatToMatlab(Eigen::Quaternionf(R));",
+    );
+}
+
+#[test]
+fn norms_and_normalization() {
+    let output = convert(
+        "function y = f(v, r, x, z)\na = v' * v;\nA = r' * r;\nb = norm(v)^2;\nc = sqrt(sum(v.^2));\nu = v / norm(v);\nx = x / norm(x);\nz(1:4) = z(1:4) / norm(z(1:4));\nw = 2 * v / norm(v);\ny = A * u * (a + b + c) + w;\nend",
+        &[
+            ("_self", (1, 1)),
+            ("v", (3, 1)),
+            ("r", (1, 3)),
+            ("x", (4, 1)),
+            ("z", (7, 1)),
+        ],
+    );
+    assert_contains(&output, "float a = v.squaredNorm();");
+    // r' * r is the outer product of a row vector
+    assert_contains(&output, "Matrix3_3f A = r.transpose() * r;");
+    assert_contains(&output, "float b = v.squaredNorm();");
+    assert_contains(&output, "float c = v.norm();");
+    assert_contains(&output, "Vector3f u = v.normalized();");
+    assert_contains(&output, "x.normalize();");
+    assert_contains(&output, "z.segment<4>(0).normalize();");
+    assert_contains(&output, "Vector3f w = 2 * v.normalized();");
+}
+
+#[test]
+fn skew_matrices_become_cross_products() {
+    let options = Options {
+        skew_functions: vec!["zetaCross".to_string()],
+        random_functions: None,
+        ..options()
+    };
+    let ti_state = types(&[
+        ("_self", (3, 1)),
+        ("w", (3, 1)),
+        ("u", (3, 1)),
+        ("zetaCross", (3, 3)),
+    ]);
+    let output = convert_with(
+        "function y = f(w, u)\nS = [0 -w(3) w(2); w(3) 0 -w(1); -w(2) w(1) 0];\nc = [0 -w(3) w(2); w(3) 0 -w(1); -w(2) w(1) 0] * u;\nZ = zetaCross(u);\nd = zetaCross(w) * u;\ny = S * u + c + Z * d;\nend",
+        ti_state,
+        &options,
+    );
+    assert_contains(&output, "inline Matrix3_3f skew(const Vector3f& v) {");
+    assert_contains(&output, "Matrix3_3f S = skew(w.segment<3>(0));");
+    assert_contains(&output, "Vector3f c = w.segment<3>(0).cross(u);");
+    assert_contains(&output, "Matrix3_3f Z = skew(u);");
+    assert_contains(&output, "Vector3f d = w.cross(u);");
+}
+
+#[test]
+fn scalar_type_sets_declarations_and_literals() {
+    let src = "function y = f(A, v, a)\nb = 0.5 * a;\nB = eye(3) + A;\ny = B * v * b;\nend";
+    let shapes = [
+        ("_self", (3, 1)),
+        ("A", (3, 3)),
+        ("v", (3, 1)),
+        ("a", (1, 1)),
+    ];
+
+    let output = convert(src, &shapes);
+    assert_contains(&output, "Vector3f f(Matrix3_3f A, Vector3f v, float a) {");
+    assert_contains(&output, "float b = 0.5f * a;");
+
+    let double = Options {
+        scalar: ScalarType::Double,
+        ..options()
+    };
+    let output = convert_with(src, types(&shapes), &double);
+    assert_contains(&output, "Vector3d f(Matrix3_3d A, Vector3d v, double a) {");
+    assert_contains(&output, "double b = 0.5 * a;");
+    assert_contains(&output, "Matrix3_3d B = Matrix3_3d::Identity() + A;");
+
+    let template = Options {
+        scalar: ScalarType::Template,
+        ..options()
+    };
+    let output = convert_with(src, types(&shapes), &template);
+    assert_contains(
+        &output,
+        "template <typename Scalar>\nEigen::Matrix<Scalar, 3, 1> f(Eigen::Matrix<Scalar, 3, 3> A, Eigen::Matrix<Scalar, 3, 1> v, Scalar a) {",
+    );
+    assert_contains(&output, "Scalar b = Scalar(0.5) * a;");
+    assert_contains(
+        &output,
+        "Eigen::Matrix<Scalar, 3, 3> B = Eigen::Matrix<Scalar, 3, 3>::Identity() + A;",
+    );
+}
+
+#[test]
+fn bool_and_int_element_types() {
+    let output = convert(
+        "function y = f(v, w, a)\nflag = a > 2;\nn = floor(a);\nm = v > w;\ncount = sum(m);\nok = any(m) && flag;\nif ok\nn = n + 1;\nend\ny = a * n + count;\nend",
+        &[
+            ("_self", (1, 1)),
+            ("v", (3, 1)),
+            ("w", (3, 1)),
+            ("a", (1, 1)),
+        ],
+    );
+    assert_contains(&output, "bool flag = a > 2;");
+    assert_contains(&output, "int n = std::floor(a);");
+    assert_contains(
+        &output,
+        "Eigen::Array<bool, 3, 1> m = v.array() > w.array();",
+    );
+    assert_contains(&output, "int count = m.count();");
+    assert_contains(&output, "bool ok = m.any() && flag;");
+    assert_contains(&output, "float y = a * n + count;");
+}
+
+#[test]
+fn declarations_are_wide_enough_for_later_assignments() {
+    let output = convert(
+        "function y = f(z)\nn = sum(z > 0);\nn = n / 2;\nm = floor(z(1));\nm = m * 0.5;\ny = n + m;\nend",
+        &[("_self", (1, 1)), ("z", (3, 1))],
+    );
+    assert_contains(&output, "float n = (z.array() > 0).count();");
+    assert_contains(&output, "n = n / 2;");
+    assert_contains(&output, "float m = std::floor(z[0]);");
+    assert_contains(&output, "float y = n + m;");
+
+    // a branch widens the variable declared before it
+    let output = convert(
+        "function y = f(a)\nk = floor(a);\nif a > 1\nk = a / 3;\nend\ny = k;\nend",
+        &[("_self", (1, 1)), ("a", (1, 1))],
+    );
+    assert_contains(&output, "float k = std::floor(a);");
+}
+
+#[test]
+fn symbolic_and_dynamic_dimensions() {
+    let mut ti_state = types(&[("a", (1, 1))]);
+    let shapes = [
+        ("_self", ("N", "1")),
+        ("x", ("N", "1")),
+        ("A", ("N", "N")),
+        ("z", ("N + 3", "1")),
+        ("d", ("Dynamic", "1")),
+    ];
+    for (name, (rows, cols)) in shapes {
+        let shape = (Dim::parse(rows), Dim::parse(cols));
+        ti_state.insert(name.to_string(), MLtType::new(ElemType::Real, shape));
+    }
+    let output = convert_with(
+        "function y = f(x, A, z, d, a)\nB = A * A';\nu = [x; z];\nv = d * a;\nw = [u; u(1)];\ny = B * x + x * a;\nend",
+        ti_state,
+        &options(),
+    );
+    assert_contains(
+        &output,
+        "template <int N>\nEigen::Matrix<float, N, 1> f(Eigen::Matrix<float, N, 1> x, Eigen::Matrix<float, N, N> A, Eigen::Matrix<float, N + 3, 1> z, VectorXf d, float a) {",
+    );
+    assert_contains(&output, "Eigen::Matrix<float, N, N> B = A * A.transpose();");
+    assert_contains(
+        &output,
+        "Eigen::Matrix<float, 2 * N + 3, 1> u = (Eigen::Matrix<float, 2 * N + 3, 1>() << x, z).finished();",
+    );
+    assert_contains(
+        &output,
+        "Eigen::Matrix<float, 2 * N + 4, 1> w = (Eigen::Matrix<float, 2 * N + 4, 1>() << u, u[0]).finished();",
+    );
+    // sizes only known at runtime
+    assert_contains(&output, "VectorXf v = d * a;");
+}
+
+#[test]
+fn project_types_reach_the_end_of_a_call_chain() {
+    // leaf is called with mid's parameter before mid's caller has given it a type
+    let output = convert_project(
+        &[
+            "function y = top(z)\ny = mid(z) * 2;\nend",
+            "function r = mid(w)\nr = leaf(w) + w;\nend",
+            "function s = leaf(v)\ns = v * 3;\nend",
+        ],
+        &[("z", (3, 1))],
+    );
+    assert_contains(&output, "Vector3f leaf(Vector3f v) {\nVector3f s = v * 3;");
+    assert_contains(
+        &output,
+        "Vector3f mid(Vector3f w) {\nVector3f r = leaf(w) + w;",
+    );
+    assert_contains(
+        &output,
+        "Vector3f top(Vector3f z) {\nVector3f y = mid(z) * 2;",
+    );
+    assert!(!output.contains("MatrixXf"));
+}
+
+#[test]
+fn project_types_are_declared_like_the_output() {
+    // the return type and the argument types are the widened declarations
+    let output = convert_project(
+        &[
+            "function y = top(x)\nk = floor(x(1));\nk = k / 2;\ny = half(x) + scale(k);\nend",
+            "function n = half(z)\nn = sum(z > 0);\nn = n / 2;\nend",
+            "function s = scale(v)\ns = v * 3;\nend",
+        ],
+        &[("x", (3, 1))],
+    );
+    assert_contains(
+        &output,
+        "float half(Vector3f z) {\nfloat n = (z.array() > 0).count();",
+    );
+    assert_contains(&output, "float scale(float v) {");
+    assert_contains(&output, "float top(Vector3f x) {");
+}
+
+#[test]
+fn project_call_cycles_are_forward_declared() {
+    let output = convert_project(
+        &[
+            "function y = c(x)\ny = d(x) * 2;\nend",
+            "function r = d(w)\nr = w;\nif w(1) > 0\nr = c(w);\nend\nend",
+        ],
+        &[("x", (3, 1))],
+    );
+    assert_contains(
+        &output,
+        "Vector3f c(Vector3f x);\n\nVector3f d(Vector3f w) {",
+    );
+}
+
+#[test]
+fn branch_assignments_are_declared_before_the_if() {
+    let output = convert(
+        "function y = f(v, a)\nif a > 0\nu = v * 2;\nk = a;\nend\nif a < 0\nu = -v;\nend\ny = u * a * k;\nend",
+        &[("v", (3, 1)), ("a", (1, 1)), ("_self", (3, 1))],
+    );
+    assert_contains(
+        &output,
+        "Vector3f u = Vector3f::Zero();\nfloat k = 0;\nif (a > 0) {\n\nu = v * 2;\nk = a;\n}",
+    );
+    // declared before the first if, so the second one assigns it
+    assert_contains(
+        &output,
+        "if (a < 0) {\n\nu = -v;\n}\nVector3f y = u * a * k;",
+    );
+}
+
+#[test]
+fn annotations_set_types_and_other_comments_stay() {
+    let output = convert(
+        "function y = f(v, z, d)\n% @param v next value\n% @param z 15x1\n%#type d :x1\n%#type P 3x3 double\nP = eye(3);\ny = P * v + z(1:3);\nend",
+        &[("v", (3, 1)), ("_self", (3, 1))],
+    );
+    assert_contains(&output, "Vector3f f(Vector3f v, Vector15f z, VectorXf d) {");
+    assert_contains(
+        &output,
+        "// @param v next value\n// z: Vector15f\n// d: VectorXf",
+    );
+    assert_contains(
+        &output,
+        "// P: Matrix3_3f\nMatrix3_3f P = Matrix3_3f::Identity();",
+    );
+}
+
+#[test]
+fn annotated_variables_are_declared_by_their_first_assignment() {
+    let output = convert(
+        "function y = f(v)\n%#type Q 3x3\n%#type k 1x1 int\nQ = eye(4);\nk = floor(v(1));\ny = Q(1:3, 1:3) * v * k;\nend",
+        &[("v", (3, 1)), ("_self", (3, 1))],
+    );
+    // declared once, with the annotated type even though it doesn't match
+    assert_contains(&output, "// Q: Matrix3_3f\n// k: int\nMatrix3_3f Q = ");
+    assert!(!output.contains("Matrix3_3f Q;"), "{}", output);
+    assert_contains(&output, "int k = std::floor(v[0]);");
+}
+
+#[test]
+fn declared_functions_are_forward_declared() {
+    let mut ti_state = types(&[
+        ("a", (3, 1)),
+        ("b", (3, 1)),
+        ("C", (3, 3)),
+        ("_self", (3, 1)),
+    ]);
+    let params = vec![
+        MLtType::real((3, 1)),
+        MLtType::real((3, 1)),
+        MLtType::real((3, 3)),
+    ];
+    ti_state.insert(
+        "StateTransitionMat".to_string(),
+        MLtType::real((9, 9)).function(params),
+    );
+    let output = convert_with(
+        "function y = f(a, b, C)\nF = StateTransitionMat(a, b, C);\ny = F(1:3, 1:3) * a;\nend",
+        ti_state,
+        &options(),
+    );
+    assert_contains(
+        &output,
+        "Matrix9_9f StateTransitionMat(Vector3f, Vector3f, Matrix3_3f);\n",
+    );
+    assert_contains(&output, "Matrix9_9f F = StateTransitionMat(a, b, C);");
+    assert_contains(&output, "Vector3f y = F.block<3, 3>(0, 0) * a;");
+}
+
+#[test]
+fn return_type_is_inferred_from_the_body() {
+    // a parameter
+    let output = convert(
+        "function x = f(x, A)\nx = A * x;\nend",
+        &[("x", (3, 1)), ("A", (3, 3))],
+    );
+    assert_contains(
+        &output,
+        "Vector3f f(Vector3f x, Matrix3_3f A) {\nx = A * x;\nreturn x;",
+    );
+
+    // a local, without a `_self` entry
+    let output = convert(
+        "function y = g(A, v)\nw = A * v;\ny = [w; 1];\nend",
+        &[("A", (3, 3)), ("v", (3, 1))],
+    );
+    assert_contains(&output, "Vector4f g(Matrix3_3f A, Vector3f v) {");
+    assert_contains(&output, "return y;");
+}
+
+#[test]
+fn shape_changes_are_renamed_or_commented_out() {
+    let output = convert(
+        "function y = g(v, P, a)\nH = [1 0 0];\nz = H * v;\nH = [1 0 0; 0 1 0];\nw = H * v;\nif a > 0\nP = [P; v'];\nend\ny = z + w(1);\nend",
+        &[("v", (3, 1)), ("P", (3, 3)), ("a", (1, 1))],
+    );
+    assert_contains(
+        &output,
+        "Matrix1_3f H = (Matrix1_3f() << 1, 0, 0).finished();",
+    );
+    assert_contains(
+        &output,
+        "Matrix2_3f H_2 = (Matrix2_3f() << 1, 0, 0, 0, 1, 0).finished();\nVector2f w = H_2 * v;",
+    );
+    // P is a parameter assigned inside an if, so it can't be renamed
+    assert_contains(
+        &output,
+        "#error \"P = (Matrix4_3f() << P, v.transpose()).finished(); changes the shape of P from 3 by 3 to 4 by 3\"",
+    );
+}
+
+#[test]
+fn shape_changes_of_known_variables() {
+    // a variable from ti_state that isn't a parameter is renamed like a local
+    let output = convert(
+        "function y = g(v)\nP = eye(4);\ny = P * [v; 1];\nend",
+        &[("v", (3, 1)), ("P", (3, 3))],
+    );
+    assert_contains(
+        &output,
+        "Matrix4_4f P_2 = Matrix4_4f::Identity();\nVector4f y = P_2 * ",
+    );
+    // a parameter passed by reference keeps its name, even in an update
+    let output = convert(
+        "function y = g(v)\npersistent P\nif isempty(P)\nP = eye(3);\nend\nP = P + v * v';\ny = v;\nend",
+        &[("v", (4, 1)), ("P", (3, 3))],
+    );
+    assert_contains(
+        &output,
+        "#error \"P = P + v * v.transpose(); changes the shape of P from 3 by 3 to 4 by 4\"",
+    );
+}
+
+#[test]
+fn complex_literals_and_builtins() {
+    let output = convert(
+        "function y = g(v)\nc = 2 + 3i;\nu = v * c;\nm = abs(u);\nr = real(u) + imag(u) + angle(u);\nk = conj(u);\na = u';\ny = m + r + abs(k);\nend",
+        &[("v", (3, 1))],
+    );
+    assert_contains(
+        &output,
+        "std::complex<float> c = 2.0f + std::complex<float>(0, 3);",
+    );
+    assert_contains(&output, "Vector3cf u = v * c;");
+    assert_contains(&output, "Vector3f m = u.cwiseAbs();");
+    assert_contains(
+        &output,
+        "Vector3f r = u.real() + u.imag() + u.array().arg().matrix();",
+    );
+    assert_contains(&output, "Vector3cf k = u.conjugate();");
+    assert_contains(&output, "Matrix1_3cf a = u.adjoint();");
+}
+
+#[test]
+fn symmetric_products_keep_the_widest_element_type() {
+    let mut options = options();
+    options.symmetric_variables = vec!["P".to_string()];
+    let mut ti_state = types(&[("A", (3, 3))]);
+    ti_state.insert("P".to_string(), MLtType::fixed(ElemType::Complex, (3, 3)));
+    let output = convert_with(
+        "function y = g(A)\nQ = A * P * A';\ny = trace(abs(Q));\nend",
+        ti_state,
+        &options,
+    );
+    assert_contains(
+        &output,
+        "Matrix3_3cf Q = A * P.selfadjointView<Eigen::Lower>() * A.transpose();",
+    );
+}
+
+#[test]
+fn conjugate_and_plain_transposes() {
+    let output = convert(
+        "function y = g(v, w)\nu = v * 1i;\nt = u';\np = u.';\nq = v.';\nr = w';\ny = abs(t * p') + q * r;\nend",
+        &[("v", (3, 1)), ("w", (1, 3))],
+    );
+    // `'` conjugates complex matrices, `.'` never does
+    assert_contains(&output, "Matrix1_3cf t = u.adjoint();");
+    assert_contains(&output, "Matrix1_3cf p = u.transpose();");
+    assert_contains(&output, "Matrix1_3f q = v.transpose();");
+    assert_contains(&output, "Vector3f r = w.transpose();");
+}
+
+#[test]
+fn complex_symmetric_products_use_the_adjoint() {
+    let mut options = options();
+    options.symmetric_variables = vec!["P".to_string()];
+    let output = convert_with(
+        "function y = g(v)\nA = v * v' * 1i;\nQ = A * P * A';\ny = trace(abs(Q));\nend",
+        types(&[("v", (3, 1)), ("P", (3, 3))]),
+        &options,
+    );
+    assert_contains(
+        &output,
+        "Matrix3_3cf Q = A * P.selfadjointView<Eigen::Lower>() * A.adjoint();",
+    );
+}
+
+#[test]
+fn matrix_construction_builtins() {
+    let output = convert(
+        "function y = g(v, A)\nR = repmat(v, 2, 1);\nl = linspace(0, 1, 5);\nh = horzcat(v, v);\nc = cat(1, v, v);\nM = reshape(A, 9, 1);\nB = blkdiag(A, eye(2));\nr = rand(3, 1);\nk = size(A, 1) + numel(v) + length(l);\ny = R(1:3) * k + M(1:3) + r + c(4:6);\nend",
+        &[("v", (3, 1)), ("A", (3, 3))],
+    );
+    assert_contains(&output, "Vector6f R = v.replicate<2, 1>();");
+    assert_contains(&output, "Matrix1_5f l = Matrix1_5f::LinSpaced(5, 0, 1);");
+    assert_contains(&output, "Matrix3_2f h = (Matrix3_2f() << v, v).finished();");
+    assert_contains(&output, "Vector6f c = (Vector6f() << v, v).finished();");
+    assert_contains(
+        &output,
+        "Vector9f M = Eigen::Map<const Vector9f>(A.data());",
+    );
+    assert_contains(
+        &output,
+        "Matrix5_5f B = (Matrix5_5f() << A, Matrix3_2f::Zero(), Matrix2_3f::Zero(), Matrix2_2f::Identity()).finished();",
+    );
+    assert_contains(&output, "Vector3f r = matlabRand<Vector3f>(3, 1);");
+    // sizes of known shapes are folded into literals
+    assert_contains(&output, "int k = 3 + 3 + 5;");
+}
+
+#[test]
+fn cat_along_other_dims_is_an_error() {
+    let output = convert(
+        "function y = g(v)\nc = cat(3, v, v);\ny = v;\nend",
+        &[("v", (3, 1))],
+    );
+    assert_contains(&output, "// c = cat(3, ...); // line could not be parsed");
+}
+
+#[test]
+fn eye_zeros_and_ones_sizes() {
+    let output = convert(
+        "function y = g(v, P, n)\nN_STATES = 3;\nI = eye(3, 4);\nZ = zeros(n, 1);\nQ = zeros(size(P));\nE = eye(N_STATES);\nO = ones(2 * 3, 1);\ny = I(1:3, 1:3) * v + Q * v + E * v + O(1:3) + Z(1:3);\nend",
+        &[("v", (3, 1)), ("P", (3, 3)), ("n", (1, 1))],
+    );
+    assert_contains(&output, "Matrix3_4f I = Matrix3_4f::Identity();");
+    // n is only known at runtime
+    assert_contains(&output, "VectorXf Z = VectorXf::Zero(n, 1);");
+    assert_contains(&output, "Matrix3_3f Q = Matrix3_3f::Zero();");
+    assert_contains(&output, "Matrix3_3f E = Matrix3_3f::Identity();");
+    assert_contains(&output, "Vector6f O = Vector6f::Ones();");
+}
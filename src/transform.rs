@@ -1,3 +1,7 @@
+use crate::aliasing::written_variable;
+use crate::builtins;
+use crate::common_subexpressions::visit_subexpressions;
+use crate::options::Options;
 use crate::syntax::*;
 
 pub fn transform_matrix_multisegment(lvalue: MLtLValue) -> MLtLValue {
@@ -8,10 +12,9 @@ pub fn transform_matrix_multisegment(lvalue: MLtLValue) -> MLtLValue {
                     segments
                         .iter()
                         .map(|mlt_range| {
-                            MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixSegment(
-                                name.clone(),
-                                mlt_range.clone(),
-                            )))
+                            vec![MLtExpr::Basic(MLtLValue::Matrix(
+                                MLtMatrixAccess::MatrixSegment(name.clone(), mlt_range.clone()),
+                            ))]
                         })
                         .collect(),
                 )
@@ -25,10 +28,10 @@ pub fn transform_matrix_multisegment(lvalue: MLtLValue) -> MLtLValue {
                     segments
                         .iter()
                         .map(|mlt_range| {
-                            MLtExpr::Basic(MLtLValue::StructMatrix(
+                            vec![MLtExpr::Basic(MLtLValue::StructMatrix(
                                 prefix.clone(),
                                 MLtMatrixAccess::MatrixSegment(name.clone(), mlt_range.clone()),
-                            ))
+                            ))]
                         })
                         .collect(),
                 )
@@ -40,18 +43,19 @@ pub fn transform_matrix_multisegment(lvalue: MLtLValue) -> MLtLValue {
     }
 }
 
+// `pi` is a constant, and `rand` / `randn` without parentheses are calls
 fn transform_pi(lvalue: MLtLValue) -> MLtLValue {
     match lvalue {
-        MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)) => {
-            if name == "pi" {
-                MLtLValue::Matrix(MLtMatrixAccess::Matrix("M_PI".to_string()))
-            } else {
-                MLtLValue::Matrix(MLtMatrixAccess::Matrix(name))
-            }
-        }
-        MLtLValue::InlineMatrix(mlt_exprs) => {
-            MLtLValue::InlineMatrix(mlt_exprs.into_iter().map(transform_expression).collect())
-        }
+        MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)) => match name.as_str() {
+            "pi" => MLtLValue::Matrix(MLtMatrixAccess::Matrix("M_PI".to_string())),
+            "rand" | "randn" => MLtLValue::FunctionCall(name, vec![]),
+            _ => MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)),
+        },
+        MLtLValue::InlineMatrix(rows) => MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(transform_expression).collect())
+                .collect(),
+        ),
         MLtLValue::FunctionCall(name, mlt_exprs) => MLtLValue::FunctionCall(
             name,
             mlt_exprs.into_iter().map(transform_expression).collect(),
@@ -60,12 +64,37 @@ fn transform_pi(lvalue: MLtLValue) -> MLtLValue {
     }
 }
 
+// `horzcat(a, b)`, `vertcat(a, b)` and `cat(dim, a, b)` are written as inline matrices, other
+// dims are left as calls and the statement becomes an error in transform_statement
+fn transform_concatenation(lvalue: MLtLValue) -> MLtLValue {
+    let MLtLValue::FunctionCall(fname, mlt_exprs) = lvalue else {
+        return lvalue;
+    };
+    let (vertical, blocks) = match (fname.as_str(), mlt_exprs.split_first()) {
+        ("horzcat", _) => (false, mlt_exprs),
+        ("vertcat", _) => (true, mlt_exprs),
+        ("cat", Some((MLtExpr::Basic(MLtLValue::Integer(dim)), blocks))) if dim == "1" => {
+            (true, blocks.to_vec())
+        }
+        ("cat", Some((MLtExpr::Basic(MLtLValue::Integer(dim)), blocks))) if dim == "2" => {
+            (false, blocks.to_vec())
+        }
+        _ => return MLtLValue::FunctionCall(fname, mlt_exprs),
+    };
+    match vertical {
+        true => MLtLValue::InlineMatrix(blocks.into_iter().map(|block| vec![block]).collect()),
+        false => MLtLValue::InlineMatrix(vec![blocks]),
+    }
+}
+
 fn transform_matrix_index(lvalue: MLtLValue) -> MLtLValue {
-    let allowed_function_calls = vec!["ones", "zeros", "eye"];
+    let allowed_function_calls = ["ones", "zeros", "eye", "rand", "randn"];
     match lvalue.clone() {
         MLtLValue::FunctionCall(fname, mlt_exprs) => match mlt_exprs.as_slice() {
             [MLtExpr::Basic(MLtLValue::Integer(idx))] => {
-                if allowed_function_calls.contains(&fname.as_str()) {
+                if allowed_function_calls.contains(&fname.as_str())
+                    || builtins::is_elementwise(&fname)
+                {
                     lvalue
                 } else {
                     MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(
@@ -81,7 +110,60 @@ fn transform_matrix_index(lvalue: MLtLValue) -> MLtLValue {
 }
 
 fn transform_lvalue(lvalue: MLtLValue) -> MLtLValue {
-    transform_matrix_index(transform_pi(transform_matrix_multisegment(lvalue)))
+    transform_matrix_index(transform_concatenation(transform_pi(
+        transform_matrix_multisegment(lvalue),
+    )))
+}
+
+fn without_parens(expr: &MLtExpr) -> &MLtExpr {
+    match expr {
+        MLtExpr::Parenthesized(e) => without_parens(e),
+        e => e,
+    }
+}
+
+// the argument of `norm(x)`
+fn norm_argument(expr: &MLtExpr) -> Option<&MLtExpr> {
+    match without_parens(expr) {
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) if fname == "norm" => {
+            match args.as_slice() {
+                [arg] => Some(arg),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// `x / norm(x)` (also as the end of a product, like `a * x / norm(x)`) and `norm(x)^2`
+fn transform_norms(expr: MLtExpr) -> MLtExpr {
+    match expr {
+        MLtExpr::BinOp(dividend, op @ (MLtBinOp::Div | MLtBinOp::CwiseDiv), divisor) => {
+            let Some(arg) = norm_argument(&divisor).map(|arg| without_parens(arg).clone()) else {
+                return MLtExpr::BinOp(dividend, op, divisor);
+            };
+            match *dividend {
+                ref x if *without_parens(x) == arg => MLtExpr::Normalized(Box::new(arg)),
+                MLtExpr::BinOp(left, MLtBinOp::Mul, ref x) if *without_parens(x) == arg => {
+                    MLtExpr::BinOp(
+                        left,
+                        MLtBinOp::Mul,
+                        Box::new(MLtExpr::Normalized(Box::new(arg))),
+                    )
+                }
+                dividend => MLtExpr::BinOp(Box::new(dividend), op, divisor),
+            }
+        }
+        MLtExpr::BinOp(base, op @ (MLtBinOp::Pow | MLtBinOp::CwisePow), exponent) => {
+            match norm_argument(&base) {
+                Some(arg) if matches!(*exponent, MLtExpr::Basic(MLtLValue::Integer(ref v)) if v == "2") => {
+                    MLtExpr::SquaredNorm(Box::new(arg.clone()))
+                }
+                _ => MLtExpr::BinOp(base, op, exponent),
+            }
+        }
+        expr => expr,
+    }
 }
 
 pub fn transform_expression(expr: MLtExpr) -> MLtExpr {
@@ -91,51 +173,102 @@ pub fn transform_expression(expr: MLtExpr) -> MLtExpr {
         MLtExpr::Transposed(mlt_expr) => {
             MLtExpr::Transposed(Box::new(transform_expression(*mlt_expr)))
         }
+        MLtExpr::PlainTransposed(mlt_expr) => {
+            MLtExpr::PlainTransposed(Box::new(transform_expression(*mlt_expr)))
+        }
         MLtExpr::Parenthesized(mlt_expr) => {
             MLtExpr::Parenthesized(Box::new(transform_expression(*mlt_expr)))
         }
-        MLtExpr::BinOp(mlt_exprl, mlt_bin_op, mlt_exprr) => MLtExpr::BinOp(
+        MLtExpr::BinOp(mlt_exprl, mlt_bin_op, mlt_exprr) => transform_norms(MLtExpr::BinOp(
             Box::new(transform_expression(*mlt_exprl)),
             mlt_bin_op,
             Box::new(transform_expression(*mlt_exprr)),
+        )),
+        MLtExpr::Normalized(mlt_expr) => {
+            MLtExpr::Normalized(Box::new(transform_expression(*mlt_expr)))
+        }
+        MLtExpr::SquaredNorm(mlt_expr) => {
+            MLtExpr::SquaredNorm(Box::new(transform_expression(*mlt_expr)))
+        }
+        MLtExpr::Skew(mlt_expr) => MLtExpr::Skew(Box::new(transform_expression(*mlt_expr))),
+        MLtExpr::SymmetricProduct(mlt_expr_a, mlt_expr_b) => MLtExpr::SymmetricProduct(
+            Box::new(transform_expression(*mlt_expr_a)),
+            Box::new(transform_expression(*mlt_expr_b)),
         ),
     }
 }
 
+// `isempty(P)` where P is persistent - this guards first call initialization
+fn persistent_isempty_target(expr: &MLtExpr, persistent_params: &[String]) -> Option<String> {
+    if let MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) = expr
+        && fname == "isempty"
+        && let [MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)))] = args.as_slice()
+        && persistent_params.contains(&format!("&{}", name))
+    {
+        return Some(name.clone());
+    }
+    None
+}
+
+// cat along any other dim makes an N-d array, which eigen doesn't have
+fn unsupported_cat_dim(expr: &MLtExpr) -> Option<String> {
+    let mut dim = None;
+    visit_subexpressions(expr, &mut |e| {
+        if let MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) = e
+            && fname == "cat"
+        {
+            dim = Some(match args.first() {
+                Some(MLtExpr::Basic(MLtLValue::Integer(dim))) => dim.clone(),
+                _ => "dim".to_string(),
+            });
+        }
+    });
+    dim
+}
+
 fn transform_statement(
     statement: MLtStatement,
     persistent_params: &mut Vec<String>,
 ) -> MLtStatement {
-    if let MLtStatement::Assignment(
-        MLtLValue::Matrix(MLtMatrixAccess::Matrix(target)),
-        MLtExpr::BinOp(dividend_expr, MLtBinOp::Div, r_expr),
-    ) = &statement
-    {
-        if let MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(ref dividend))) =
-            **dividend_expr
-        {
-            if let MLtExpr::Basic(MLtLValue::FunctionCall(ref fname, ref args)) = **r_expr {
-                if fname == "norm"
-                    && args.len() == 1
-                    && matches!(&args[0], MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(arg))) if arg == dividend && arg == target)
-                {
-                    return MLtStatement::Normalization(target.clone());
-                }
-            }
+    if let MLtStatement::IfStatement(expr, body) = statement {
+        let body = body
+            .into_iter()
+            .map(|s| transform_statement(s, persistent_params))
+            .collect();
+        if let Some(name) = persistent_isempty_target(&expr, persistent_params) {
+            return MLtStatement::PersistentInit(name, body);
         }
+        return MLtStatement::IfStatement(transform_expression(expr), body);
     }
 
-    if let MLtStatement::IfStatement(expr, body) = statement {
-        return MLtStatement::IfStatement(
-            transform_expression(expr),
-            body.into_iter()
-                .map(|s| transform_statement(s, persistent_params))
-                .collect(),
-        );
+    if let MLtStatement::Assignment(left, right) = statement {
+        let left = transform_lvalue(left);
+        let right = transform_expression(right);
+        if let Some(dim) = unsupported_cat_dim(&right) {
+            return MLtStatement::Error(format!(
+                "{} = cat({}, ...)",
+                written_variable(&left).unwrap_or_default(),
+                dim
+            ));
+        }
+        // x = x / norm(x), including segments and struct members
+        if let MLtExpr::Normalized(normalized) = &right
+            && matches!(&**normalized, MLtExpr::Basic(lvalue) if *lvalue == left)
+        {
+            return MLtStatement::Normalization(left);
+        }
+        return MLtStatement::Assignment(left, right);
     }
 
-    if let MLtStatement::Assignment(left, right) = statement {
-        return MLtStatement::Assignment(transform_lvalue(left), transform_expression(right));
+    if let MLtStatement::MultiAssignment(lefts, right) = statement {
+        let right = transform_expression(right);
+        if let Some(dim) = unsupported_cat_dim(&right) {
+            return MLtStatement::Error(format!("[...] = cat({}, ...)", dim));
+        }
+        return MLtStatement::MultiAssignment(
+            lefts.into_iter().map(transform_lvalue).collect(),
+            right,
+        );
     }
 
     if let MLtStatement::Persistent(new_persis_params) = statement.clone() {
@@ -145,7 +278,450 @@ fn transform_statement(
     statement
 }
 
-pub fn transform_ast(mut function: MLtFunction) -> MLtFunction {
+fn is_number(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(_) | MLtLValue::Float(_)) => true,
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e) => is_number(e),
+        _ => false,
+    }
+}
+
+fn is_square_size(args: &[MLtExpr]) -> bool {
+    match args {
+        // `zeros(size(A))` is the shape of A
+        [MLtExpr::Basic(MLtLValue::FunctionCall(fname, _))] if fname == "size" => false,
+        [_] => true,
+        [rows, cols] => rows == cols,
+        _ => false,
+    }
+}
+
+fn is_one(expr: &MLtExpr) -> bool {
+    matches!(expr, MLtExpr::Basic(MLtLValue::Integer(i)) if i == "1")
+}
+
+// vectors that can be told apart from matrices without types, anything else passed to diag could
+// be a matrix, whose diagonal is a column vector
+fn is_vector(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Matrix(
+            MLtMatrixAccess::MatrixIndex(_, _)
+            | MLtMatrixAccess::MatrixSegment(_, _)
+            | MLtMatrixAccess::MatrixMultiSegment(_, _),
+        )) => true,
+        _ => is_number(expr) || is_column(expr) || is_row(expr),
+    }
+}
+
+fn is_scalar_element(expr: &MLtExpr) -> bool {
+    is_number(expr)
+        || matches!(
+            expr,
+            MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(_, _)))
+        )
+}
+
+fn is_column(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args))
+            if fname == "zeros" || fname == "ones" =>
+        {
+            matches!(args.as_slice(), [_, cols] if is_one(cols))
+        }
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => rows
+            .iter()
+            .all(|row| matches!(row.as_slice(), [e] if is_scalar_element(e) || is_column(e))),
+        // anything times a column is a column
+        MLtExpr::BinOp(_, MLtBinOp::Mul, right) => is_column(right),
+        MLtExpr::BinOp(left, MLtBinOp::Div, right) if is_number(right) => is_column(left),
+        MLtExpr::BinOp(left, MLtBinOp::Add | MLtBinOp::Sub, right) => {
+            (is_column(left) || is_number(left)) && (is_column(right) || is_number(right))
+        }
+        MLtExpr::Transposed(e) | MLtExpr::PlainTransposed(e) => is_row(e),
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e) => is_column(e),
+        _ => false,
+    }
+}
+
+fn is_row(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args))
+            if fname == "zeros" || fname == "ones" =>
+        {
+            matches!(args.as_slice(), [rows, _] if is_one(rows))
+        }
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => {
+            rows.len() == 1 && rows[0].iter().all(|e| is_scalar_element(e) || is_row(e))
+        }
+        // a row times anything is a row
+        MLtExpr::BinOp(left, MLtBinOp::Mul, _) => is_row(left),
+        MLtExpr::BinOp(left, MLtBinOp::Div, right) if is_number(right) => is_row(left),
+        MLtExpr::BinOp(left, MLtBinOp::Add | MLtBinOp::Sub, right) => {
+            (is_row(left) || is_number(left)) && (is_row(right) || is_number(right))
+        }
+        MLtExpr::Transposed(e) | MLtExpr::PlainTransposed(e) => is_column(e),
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e) => is_row(e),
+        _ => false,
+    }
+}
+
+fn is_symmetric(expr: &MLtExpr, symmetric: &[String]) -> bool {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(name))) => {
+            symmetric.contains(name)
+        }
+        MLtExpr::Basic(MLtLValue::StructMatrix(prefix, MLtMatrixAccess::Matrix(name))) => {
+            symmetric.contains(&format!("{}.{}", prefix, name))
+        }
+        // blocks on the diagonal of a symmetric matrix
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixBlock(name, rows, cols))) => {
+            rows == cols && symmetric.contains(name)
+        }
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) => match fname.as_str() {
+            "eye" | "zeros" | "ones" => is_square_size(args),
+            // only the diagonal matrix of a vector, `diag(A)` is A's diagonal as a vector
+            "diag" => args.first().is_some_and(is_vector),
+            "inv" => args.first().is_some_and(|a| is_symmetric(a, symmetric)),
+            _ => false,
+        },
+        MLtExpr::Basic(_) | MLtExpr::SquaredNorm(_) | MLtExpr::Skew(_) => false,
+        MLtExpr::SymmetricProduct(_, _) => true,
+        MLtExpr::Negation(e)
+        | MLtExpr::Transposed(e)
+        | MLtExpr::PlainTransposed(e)
+        | MLtExpr::Parenthesized(e)
+        | MLtExpr::Normalized(e) => is_symmetric(e, symmetric),
+        MLtExpr::BinOp(left, MLtBinOp::Add | MLtBinOp::Sub, right) => {
+            is_symmetric(left, symmetric) && is_symmetric(right, symmetric)
+        }
+        // scaling by a number
+        MLtExpr::BinOp(left, MLtBinOp::Mul, right) if is_number(left) => {
+            is_symmetric(right, symmetric)
+        }
+        MLtExpr::BinOp(left, MLtBinOp::Mul | MLtBinOp::Div, right) if is_number(right) => {
+            is_symmetric(left, symmetric)
+        }
+        MLtExpr::BinOp(_, _, _) => false,
+    }
+}
+
+// A * B * A' where B is symmetric - A is used twice, so when it isn't a variable it is computed
+// once into a temporary pushed to `hoisted`
+fn detect_symmetric_products(
+    expr: MLtExpr,
+    symmetric: &[String],
+    hoisted: &mut Vec<MLtStatement>,
+    temp_count: &mut u32,
+) -> MLtExpr {
+    let mut detect = |e: Box<MLtExpr>| {
+        Box::new(detect_symmetric_products(
+            *e, symmetric, hoisted, temp_count,
+        ))
+    };
+    match expr {
+        MLtExpr::BinOp(left, MLtBinOp::Mul, right) => {
+            if let MLtExpr::BinOp(a, MLtBinOp::Mul, b) = &*left
+                && let MLtExpr::Transposed(a_t) = &*right
+                && without_parens(a) == without_parens(a_t)
+                && is_symmetric(b, symmetric)
+            {
+                let a = detect(a.clone());
+                let b = detect(b.clone());
+                if let MLtExpr::Basic(MLtLValue::Matrix(_) | MLtLValue::StructMatrix(_, _)) = *a {
+                    return MLtExpr::SymmetricProduct(a, b);
+                }
+                let temp_name = format!("sandwich_{}", temp_count);
+                *temp_count += 1;
+                hoisted.push(MLtStatement::Temporary(
+                    temp_name.clone(),
+                    without_parens(&a).clone(),
+                ));
+                let temp = MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::Matrix(temp_name)));
+                return MLtExpr::SymmetricProduct(Box::new(temp), b);
+            }
+            MLtExpr::BinOp(detect(left), MLtBinOp::Mul, detect(right))
+        }
+        MLtExpr::BinOp(left, op, right) => MLtExpr::BinOp(detect(left), op, detect(right)),
+        MLtExpr::Negation(e) => MLtExpr::Negation(detect(e)),
+        MLtExpr::Transposed(e) => MLtExpr::Transposed(detect(e)),
+        MLtExpr::PlainTransposed(e) => MLtExpr::PlainTransposed(detect(e)),
+        MLtExpr::Parenthesized(e) => MLtExpr::Parenthesized(detect(e)),
+        MLtExpr::Normalized(e) => MLtExpr::Normalized(detect(e)),
+        MLtExpr::SquaredNorm(e) => MLtExpr::SquaredNorm(detect(e)),
+        MLtExpr::Skew(e) => MLtExpr::Skew(detect(e)),
+        MLtExpr::SymmetricProduct(a, b) => MLtExpr::SymmetricProduct(detect(a), detect(b)),
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) => {
+            let args: Vec<MLtExpr> = args
+                .into_iter()
+                .map(|e| detect_symmetric_products(e, symmetric, hoisted, temp_count))
+                .collect();
+            // the eigenvalues of a symmetric matrix are real, and there is a faster solver for it
+            let fname = match args.as_slice() {
+                [arg] if fname == "eig" && is_symmetric(arg, symmetric) => {
+                    "selfadjoint_eig".to_string()
+                }
+                _ => fname,
+            };
+            MLtExpr::Basic(MLtLValue::FunctionCall(fname, args))
+        }
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => MLtExpr::Basic(MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|e| detect_symmetric_products(e, symmetric, hoisted, temp_count))
+                        .collect()
+                })
+                .collect(),
+        )),
+        MLtExpr::Basic(_) => expr,
+    }
+}
+
+fn contains_symmetric_product(expr: &MLtExpr) -> bool {
+    match expr {
+        MLtExpr::SymmetricProduct(_, _) => true,
+        MLtExpr::Negation(e)
+        | MLtExpr::Transposed(e)
+        | MLtExpr::PlainTransposed(e)
+        | MLtExpr::Parenthesized(e)
+        | MLtExpr::Normalized(e)
+        | MLtExpr::SquaredNorm(e)
+        | MLtExpr::Skew(e) => contains_symmetric_product(e),
+        MLtExpr::BinOp(left, _, right) => {
+            contains_symmetric_product(left) || contains_symmetric_product(right)
+        }
+        MLtExpr::Basic(_) => false,
+    }
+}
+
+// tracks which variables are symmetric through the function, so products with them can use
+// selfadjointView - symmetric is updated by each assignment
+fn transform_symmetric(
+    statements: Vec<MLtStatement>,
+    symmetric: &mut Vec<String>,
+    temp_count: &mut u32,
+    options: &Options,
+) -> Vec<MLtStatement> {
+    let mut transformed = vec![];
+    // symmetrizing goes on its own line after the assignment
+    let mut symmetrize_after_line = None;
+    for statement in statements {
+        match statement {
+            MLtStatement::NewLine => {
+                transformed.push(statement);
+                if let Some(name) = symmetrize_after_line.take() {
+                    transformed.push(MLtStatement::Symmetrize(name));
+                }
+            }
+            MLtStatement::Assignment(lvalue, expr) => {
+                let mut hoisted = vec![];
+                let expr = detect_symmetric_products(expr, symmetric, &mut hoisted, temp_count);
+                transformed.extend(hoisted);
+                let expr_symmetric = is_symmetric(&expr, symmetric);
+                let symmetrize = options.symmetrize && contains_symmetric_product(&expr);
+                match &lvalue {
+                    MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)) => {
+                        symmetric.retain(|s| s != name);
+                        if expr_symmetric {
+                            symmetric.push(name.clone());
+                        }
+                        transformed.push(MLtStatement::Assignment(lvalue.clone(), expr));
+                        if expr_symmetric && symmetrize {
+                            symmetrize_after_line = Some(name.clone());
+                        }
+                        continue;
+                    }
+                    // writing a symmetric block onto the diagonal keeps the matrix symmetric
+                    MLtLValue::Matrix(MLtMatrixAccess::MatrixBlock(name, rows, cols))
+                        if rows == cols && expr_symmetric => {}
+                    MLtLValue::Matrix(
+                        MLtMatrixAccess::MatrixIndex(name, _)
+                        | MLtMatrixAccess::MatrixSegment(name, _)
+                        | MLtMatrixAccess::MatrixMultiSegment(name, _)
+                        | MLtMatrixAccess::MatrixBlock(name, _, _),
+                    ) => symmetric.retain(|s| s != name),
+                    MLtLValue::StructMatrix(prefix, MLtMatrixAccess::Matrix(name)) => {
+                        let name = format!("{}.{}", prefix, name);
+                        symmetric.retain(|s| *s != name);
+                        if expr_symmetric {
+                            symmetric.push(name);
+                        }
+                    }
+                    MLtLValue::StructMatrix(_, MLtMatrixAccess::MatrixBlock(_, rows, cols))
+                        if rows == cols && expr_symmetric => {}
+                    MLtLValue::StructMatrix(
+                        prefix,
+                        MLtMatrixAccess::MatrixIndex(name, _)
+                        | MLtMatrixAccess::MatrixSegment(name, _)
+                        | MLtMatrixAccess::MatrixMultiSegment(name, _)
+                        | MLtMatrixAccess::MatrixBlock(name, _, _),
+                    ) => {
+                        let name = format!("{}.{}", prefix, name);
+                        symmetric.retain(|s| *s != name);
+                    }
+                    _ => {}
+                }
+                transformed.push(MLtStatement::Assignment(lvalue, expr));
+            }
+            MLtStatement::MultiAssignment(lvalues, expr) => {
+                let mut hoisted = vec![];
+                let expr = detect_symmetric_products(expr, symmetric, &mut hoisted, temp_count);
+                transformed.extend(hoisted);
+                for lvalue in &lvalues {
+                    if let MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)) = lvalue {
+                        symmetric.retain(|s| s != name);
+                    }
+                }
+                transformed.push(MLtStatement::MultiAssignment(lvalues, expr));
+            }
+            MLtStatement::Normalization(ref lvalue) => {
+                if let Some(name) = written_variable(lvalue) {
+                    symmetric.retain(|s| *s != name);
+                }
+                transformed.push(statement);
+            }
+            // only variables that are symmetric whether or not the body runs stay symmetric
+            MLtStatement::IfStatement(cond, body) => {
+                let mut body_symmetric = symmetric.clone();
+                let body = transform_symmetric(body, &mut body_symmetric, temp_count, options);
+                symmetric.retain(|s| body_symmetric.contains(s));
+                transformed.push(MLtStatement::IfStatement(cond, body));
+            }
+            MLtStatement::PersistentInit(name, body) => {
+                let mut body_symmetric = symmetric.clone();
+                let body = transform_symmetric(body, &mut body_symmetric, temp_count, options);
+                symmetric.retain(|s| body_symmetric.contains(s));
+                transformed.push(MLtStatement::PersistentInit(name, body));
+            }
+            statement => transformed.push(statement),
+        }
+    }
+    transformed.extend(symmetrize_after_line.map(MLtStatement::Symmetrize));
+    transformed
+}
+
+// rebuilds an expression bottom up, calling `f` on each subexpression after its children
+fn map_expression(expr: MLtExpr, f: &impl Fn(MLtExpr) -> MLtExpr) -> MLtExpr {
+    let map_boxed = |e: Box<MLtExpr>| Box::new(map_expression(*e, f));
+    let expr = match expr {
+        MLtExpr::Basic(MLtLValue::InlineMatrix(rows)) => MLtExpr::Basic(MLtLValue::InlineMatrix(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(|e| map_expression(e, f)).collect())
+                .collect(),
+        )),
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, args)) => {
+            MLtExpr::Basic(MLtLValue::FunctionCall(
+                fname,
+                args.into_iter().map(|e| map_expression(e, f)).collect(),
+            ))
+        }
+        MLtExpr::Basic(_) => expr,
+        MLtExpr::Negation(e) => MLtExpr::Negation(map_boxed(e)),
+        MLtExpr::Transposed(e) => MLtExpr::Transposed(map_boxed(e)),
+        MLtExpr::PlainTransposed(e) => MLtExpr::PlainTransposed(map_boxed(e)),
+        MLtExpr::Parenthesized(e) => MLtExpr::Parenthesized(map_boxed(e)),
+        MLtExpr::Normalized(e) => MLtExpr::Normalized(map_boxed(e)),
+        MLtExpr::SquaredNorm(e) => MLtExpr::SquaredNorm(map_boxed(e)),
+        MLtExpr::Skew(e) => MLtExpr::Skew(map_boxed(e)),
+        MLtExpr::BinOp(left, op, right) => MLtExpr::BinOp(map_boxed(left), op, map_boxed(right)),
+        MLtExpr::SymmetricProduct(a, b) => MLtExpr::SymmetricProduct(map_boxed(a), map_boxed(b)),
+    };
+    f(expr)
+}
+
+fn map_statement_expressions(
+    statements: Vec<MLtStatement>,
+    f: &impl Fn(MLtExpr) -> MLtExpr,
+) -> Vec<MLtStatement> {
+    statements
+        .into_iter()
+        .map(|statement| match statement {
+            MLtStatement::Assignment(lvalue, expr) => {
+                MLtStatement::Assignment(lvalue, map_expression(expr, f))
+            }
+            MLtStatement::MultiAssignment(lvalues, expr) => {
+                MLtStatement::MultiAssignment(lvalues, map_expression(expr, f))
+            }
+            MLtStatement::IfStatement(cond, body) => MLtStatement::IfStatement(
+                map_expression(cond, f),
+                map_statement_expressions(body, f),
+            ),
+            MLtStatement::PersistentInit(name, body) => {
+                MLtStatement::PersistentInit(name, map_statement_expressions(body, f))
+            }
+            statement => statement,
+        })
+        .collect()
+}
+
+fn is_zero(expr: &MLtExpr) -> bool {
+    matches!(expr, MLtExpr::Basic(MLtLValue::Integer(v)) if v == "0")
+}
+
+fn is_negation_of(expr: &MLtExpr, value: &MLtExpr) -> bool {
+    match (expr, value) {
+        (MLtExpr::Negation(e), value) => **e == *value,
+        (expr, MLtExpr::Negation(v)) => *expr == **v,
+        _ => false,
+    }
+}
+
+// `[w(1); w(2); w(3)]` is the segment `w(1:3)`
+fn skew_vector(a: &MLtExpr, b: &MLtExpr, c: &MLtExpr) -> MLtExpr {
+    if let (
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(name, start))),
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(name_b, idx_b))),
+        MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixIndex(name_c, idx_c))),
+    ) = (a, b, c)
+        && name == name_b
+        && name == name_c
+        && *idx_b == start + 1
+        && *idx_c == start + 2
+    {
+        let range = MLtRange {
+            start: *start,
+            end: start + 2,
+        };
+        return MLtExpr::Basic(MLtLValue::Matrix(MLtMatrixAccess::MatrixSegment(
+            name.clone(),
+            range,
+        )));
+    }
+    MLtExpr::Basic(MLtLValue::InlineMatrix(vec![
+        vec![a.clone()],
+        vec![b.clone()],
+        vec![c.clone()],
+    ]))
+}
+
+// `[0 -c b; c 0 -a; -b a 0]` or a call to one of the configured skew functions
+fn detect_skew(expr: MLtExpr, skew_functions: &[String]) -> MLtExpr {
+    match expr {
+        MLtExpr::Basic(MLtLValue::FunctionCall(ref fname, ref args))
+            if skew_functions.contains(fname) && args.len() == 1 =>
+        {
+            MLtExpr::Skew(Box::new(args[0].clone()))
+        }
+        MLtExpr::Basic(MLtLValue::InlineMatrix(ref rows)) => {
+            if let [[m00, m01, m02], [m10, m11, m12], [m20, m21, m22]] = rows
+                .iter()
+                .map(|row| row.as_slice())
+                .collect::<Vec<_>>()
+                .as_slice()
+                && [m00, m11, m22].iter().all(|m| is_zero(m))
+                && is_negation_of(m01, m10)
+                && is_negation_of(m12, m21)
+                && is_negation_of(m20, m02)
+            {
+                return MLtExpr::Skew(Box::new(skew_vector(m21, m02, m10)));
+            }
+            expr
+        }
+        expr => expr,
+    }
+}
+
+pub fn transform_ast(mut function: MLtFunction, options: &Options) -> MLtFunction {
     let mut persistent_params = vec![];
     function.body = function
         .body
@@ -154,5 +730,11 @@ pub fn transform_ast(mut function: MLtFunction) -> MLtFunction {
         .collect();
     function.params.extend(persistent_params);
 
+    function.body =
+        map_statement_expressions(function.body, &|e| detect_skew(e, &options.skew_functions));
+
+    let mut symmetric = options.symmetric_variables.clone();
+    function.body = transform_symmetric(function.body, &mut symmetric, &mut 0, options);
+
     function
 }
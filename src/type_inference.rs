@@ -1,194 +1,1295 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Add, Mul};
 
+use crate::builtins;
 use crate::syntax::*;
 
-// returns the type (rows, cols) of a matlab expression so the C++ type can be inserted
+// returns the type (element type, rows, cols) of a matlab expression so the C++ type can be inserted
+// - `quiet` turns the type warnings off, for the passes that infer types again before the output
 
+// ordered from narrowest to widest, so the type of mixed arithmetic is the max of its operands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ElemType {
+    Bool,    // comparisons, any, all, isempty
+    Int,     // integer literals and the builtins that keep them integers, only used for scalars
+    Real,    // float or double, depending on the `scalar` option
+    Complex, // imaginary literals and anything computed from them
+}
+
+// a matrix dimension - known, a template parameter of the generated function, or only known at
+// runtime (which is also what anything that couldn't be inferred becomes)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dim {
+    Fixed(u32),
+    Symbolic(Vec<String>, u32), // sum of template parameters plus a constant, `N + M + 3`, sorted
+    Dynamic,
+}
+
+impl Dim {
+    // "3", "N", "N + 3", or "Dynamic" (":" in annotations)
+    pub fn parse(s: &str) -> Dim {
+        if matches!(s.trim(), "Dynamic" | ":") {
+            return Dim::Dynamic;
+        }
+        s.split('+')
+            .map(|term| {
+                let term = term.trim();
+                match term.parse() {
+                    Ok(n) => Dim::Fixed(n),
+                    Err(_) => Dim::Symbolic(vec![term.to_string()], 0),
+                }
+            })
+            .fold(Dim::Fixed(0), |sum, term| sum + term)
+    }
+
+    // whether two dimensions are known to be different - dynamic sizes could be anything
+    pub fn conflicts(&self, other: &Dim) -> bool {
+        *self != Dim::Dynamic && *other != Dim::Dynamic && self != other
+    }
+
+    pub fn min(self, other: Dim) -> Dim {
+        match (self, other) {
+            (Dim::Fixed(a), Dim::Fixed(b)) => Dim::Fixed(a.min(b)),
+            (a, b) if a == b => a,
+            _ => Dim::Dynamic,
+        }
+    }
+
+    // template parameters used in the dimension
+    pub fn parameters(&self) -> Vec<String> {
+        match self {
+            Dim::Symbolic(names, _) => names.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+impl PartialEq<u32> for Dim {
+    fn eq(&self, other: &u32) -> bool {
+        *self == Dim::Fixed(*other)
+    }
+}
+
+impl Add for Dim {
+    type Output = Dim;
+    fn add(self, other: Dim) -> Dim {
+        match (self, other) {
+            (Dim::Dynamic, _) | (_, Dim::Dynamic) => Dim::Dynamic,
+            (Dim::Fixed(a), Dim::Fixed(b)) => Dim::Fixed(a + b),
+            (Dim::Fixed(a), Dim::Symbolic(names, b)) | (Dim::Symbolic(names, b), Dim::Fixed(a)) => {
+                Dim::Symbolic(names, a + b)
+            }
+            (Dim::Symbolic(mut names, a), Dim::Symbolic(other_names, b)) => {
+                names.extend(other_names);
+                names.sort();
+                Dim::Symbolic(names, a + b)
+            }
+        }
+    }
+}
+
+// only products with a constant stay symbolic
+impl Mul for Dim {
+    type Output = Dim;
+    fn mul(self, other: Dim) -> Dim {
+        match (self, other) {
+            (Dim::Fixed(0), _) | (_, Dim::Fixed(0)) => Dim::Fixed(0),
+            (Dim::Fixed(a), Dim::Fixed(b)) => Dim::Fixed(a * b),
+            (Dim::Fixed(a), Dim::Symbolic(names, b)) | (Dim::Symbolic(names, b), Dim::Fixed(a)) => {
+                let mut repeated: Vec<String> = (0..a).flat_map(|_| names.clone()).collect();
+                repeated.sort();
+                Dim::Symbolic(repeated, a * b)
+            }
+            _ => Dim::Dynamic,
+        }
+    }
+}
+
+// written as the C++ template argument, repeated parameters are multiplied (`2 * N + 1`)
+impl fmt::Display for Dim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dim::Fixed(n) => write!(f, "{}", n),
+            Dim::Symbolic(names, n) => {
+                let mut terms = vec![];
+                for name in names {
+                    match terms.last_mut() {
+                        Some((last, count)) if last == name => *count += 1,
+                        _ => terms.push((name.clone(), 1)),
+                    }
+                }
+                let mut terms: Vec<String> = terms
+                    .into_iter()
+                    .map(|(name, count)| match count {
+                        1 => name,
+                        count => format!("{} * {}", count, name),
+                    })
+                    .collect();
+                if *n != 0 {
+                    terms.push(n.to_string());
+                }
+                write!(f, "{}", terms.join(" + "))
+            }
+            Dim::Dynamic => write!(f, "Eigen::Dynamic"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MLtType {
+    pub elem: ElemType,
+    pub rows: Dim,
+    pub cols: Dim,
+    pub params: Option<Vec<MLtType>>, // parameter types, for functions that declare them
+}
+
+impl MLtType {
+    // matlab integers are doubles, and eigen can't mix int and float matrices without a cast, so
+    // int matrices are real
+    pub fn new(elem: ElemType, (rows, cols): (Dim, Dim)) -> MLtType {
+        let elem = match elem {
+            ElemType::Int if rows != 1 || cols != 1 => ElemType::Real,
+            elem => elem,
+        };
+        MLtType {
+            elem,
+            rows,
+            cols,
+            params: None,
+        }
+    }
+
+    // a function returning `self`, with calls checked against the parameter types
+    pub fn function(self, params: Vec<MLtType>) -> MLtType {
+        MLtType {
+            params: Some(params),
+            ..self
+        }
+    }
+
+    pub fn fixed(elem: ElemType, (rows, cols): (u32, u32)) -> MLtType {
+        MLtType::new(elem, (Dim::Fixed(rows), Dim::Fixed(cols)))
+    }
+
+    pub fn real((rows, cols): (u32, u32)) -> MLtType {
+        MLtType::fixed(ElemType::Real, (rows, cols))
+    }
+
+    pub fn dynamic() -> MLtType {
+        MLtType::new(ElemType::Real, (Dim::Dynamic, Dim::Dynamic))
+    }
+
+    pub fn shape(&self) -> (Dim, Dim) {
+        (self.rows.clone(), self.cols.clone())
+    }
+
+    // the fixed size shape, if both dimensions are known
+    pub fn fixed_shape(&self) -> Option<(u32, u32)> {
+        match (&self.rows, &self.cols) {
+            (Dim::Fixed(rows), Dim::Fixed(cols)) => Some((*rows, *cols)),
+            _ => None,
+        }
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        self.fixed_shape() == Some((1, 1))
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.rows == Dim::Dynamic || self.cols == Dim::Dynamic
+    }
+
+    // whether two shapes are known to be different
+    pub fn conflicts(&self, other: &MLtType) -> bool {
+        self.rows.conflicts(&other.rows) || self.cols.conflicts(&other.cols)
+    }
+
+    pub fn with_shape(&self, shape: (Dim, Dim)) -> MLtType {
+        MLtType::new(self.elem, shape)
+    }
+
+    pub fn with_elem(&self, elem: ElemType) -> MLtType {
+        MLtType::new(elem, self.shape())
+    }
+}
+
+// elements in a row are concatenated horizontally
+fn inline_row_type(
+    row: &[MLtExpr],
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> MLtType {
+    let first = expr_type(
+        row.first()
+            .expect("Inline matrix rows must have at least one element"),
+        ti_state,
+        line_num,
+        quiet,
+    );
+    let (rows, mut cols) = first.shape();
+    let mut elem = first.elem;
+    for expr in row.iter().skip(1) {
+        let new_type = expr_type(expr, ti_state, line_num, quiet);
+        let (new_rows, new_cols) = new_type.shape();
+        if !quiet && rows.conflicts(&new_rows) {
+            println!(
+                "Inline matrix type warning: horizontal concat: {} by {} with {} by {} on line {}.",
+                rows, cols, new_rows, new_cols, line_num
+            );
+        }
+        cols = cols + new_cols;
+        elem = elem.max(new_type.elem);
+    }
+    MLtType::new(elem, (rows, cols))
+}
+
+// then the rows are concatenated vertically
 pub fn inline_matrix_type(
-    exprs: &Vec<MLtExpr>,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    rows: &[Vec<MLtExpr>],
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
-) -> (u32, u32) {
-    let (mut rows, cols) = expr_type(
-        exprs
-            .get(0)
+    quiet: bool,
+) -> MLtType {
+    let first = inline_row_type(
+        rows.first()
             .expect("Inline matrix must have at least one element"),
         ti_state,
         line_num,
+        quiet,
     );
-    for expr in exprs.iter().skip(1) {
-        let (new_rows, new_cols) = expr_type(expr, ti_state, line_num);
-        if cols != new_cols {
+    let (mut rows_total, cols) = first.shape();
+    let mut elem = first.elem;
+    for row in rows.iter().skip(1) {
+        let new_type = inline_row_type(row, ti_state, line_num, quiet);
+        let (new_rows, new_cols) = new_type.shape();
+        if !quiet && cols.conflicts(&new_cols) {
             println!(
                 "Inline matrix type warning: concat: {} by {} with {} by {} on line {}.",
-                rows, cols, new_rows, new_cols, line_num
+                rows_total, cols, new_rows, new_cols, line_num
             );
         }
-        rows += new_rows;
+        rows_total = rows_total + new_rows;
+        elem = elem.max(new_type.elem);
     }
-    (rows, cols)
+    MLtType::new(elem, (rows_total, cols))
 }
 
 fn matrix_type(
     prefix: &str,
     matrix: &MLtMatrixAccess,
-    ti_state: &mut HashMap<String, (u32, u32)>,
-) -> (u32, u32) {
+    ti_state: &mut HashMap<String, MLtType>,
+    quiet: bool,
+) -> MLtType {
+    let name = match matrix {
+        MLtMatrixAccess::Matrix(name)
+        | MLtMatrixAccess::MatrixIndex(name, _)
+        | MLtMatrixAccess::MatrixSegment(name, _)
+        | MLtMatrixAccess::MatrixMultiSegment(name, _)
+        | MLtMatrixAccess::MatrixBlock(name, _, _) => format!("{}{}", prefix, name),
+    };
+    // accesses into a matrix that isn't known yet have been warned about when it was assigned
+    let elem = ti_state.get(&name).map_or(ElemType::Real, |t| t.elem);
     match matrix {
-        MLtMatrixAccess::Matrix(name) => {
-            if let Some((rows, cols)) = ti_state.get(format!("{}{}", prefix, name).as_str()) {
-                (*rows, *cols)
+        MLtMatrixAccess::Matrix(_) => {
+            if let Some(t) = ti_state.get(&name) {
+                t.clone()
+            } else if prefix.is_empty() && (name == "true" || name == "false") {
+                MLtType::fixed(ElemType::Bool, (1, 1))
             } else {
-                println!("Couldn't find {}{} in types", prefix, name);
-                (0, 0)
+                if !quiet {
+                    println!("Couldn't find {} in types", name);
+                }
+                MLtType::dynamic()
             }
         }
-        MLtMatrixAccess::MatrixIndex(_, _) => (1, 1),
-        MLtMatrixAccess::MatrixSegment(_, mlt_range) => (mlt_range.end - mlt_range.start + 1, 1),
+        MLtMatrixAccess::MatrixIndex(_, _) => MLtType::fixed(elem, (1, 1)),
+        MLtMatrixAccess::MatrixSegment(_, mlt_range) => {
+            MLtType::fixed(elem, (mlt_range.end - mlt_range.start + 1, 1))
+        }
         MLtMatrixAccess::MatrixMultiSegment(_, _) => {
             panic!("MatrixMultiSegment should be converted to an inline matrix")
         }
         MLtMatrixAccess::MatrixBlock(_, rows, cols) => {
-            (rows.end - rows.start + 1, cols.end - cols.start + 1)
+            MLtType::fixed(elem, (rows.end - rows.start + 1, cols.end - cols.start + 1))
+        }
+    }
+}
+
+// which way a reduction (sum, prod, mean, any, all, min, max) collapses its argument
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reduction {
+    Full,    // vectors reduce to a scalar
+    Colwise, // matrices reduce each column by default, or with dim = 1
+    Rowwise, // dim = 2
+    None,    // dim >= 3, every element is reduced on its own
+}
+
+// matlab reduces along the first non-singleton dimension unless `dim` is given
+pub fn reduction_kind((rows, cols): (Dim, Dim), dim: Option<&MLtExpr>) -> Reduction {
+    match dim {
+        Some(MLtExpr::Basic(MLtLValue::Integer(dim))) => match dim.as_str() {
+            "1" => Reduction::Colwise,
+            "2" => Reduction::Rowwise,
+            _ => Reduction::None,
+        },
+        // a runtime dim can't be resolved, reduction_type warns about it
+        Some(_) | None if rows == 1 || cols == 1 => Reduction::Full,
+        Some(_) | None => Reduction::Colwise,
+    }
+}
+
+fn reduction_type(
+    function_params: &[MLtExpr],
+    dim_idx: usize,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+    elem: ElemType,
+) -> MLtType {
+    let (rows, cols) = expr_type(
+        function_params
+            .first()
+            .expect("reduction expects at least one matrix argument"),
+        ti_state,
+        line_num,
+        quiet,
+    )
+    .shape();
+    let dim = function_params.get(dim_idx);
+    if !quiet
+        && let Some(dim) = dim
+        && !matches!(dim, MLtExpr::Basic(MLtLValue::Integer(_)))
+    {
+        println!(
+            "Reduction warning: dim is not an integer literal, reducing along the default dimension on line {}.",
+            line_num
+        );
+    }
+    let shape = match reduction_kind((rows.clone(), cols.clone()), dim) {
+        Reduction::Full => (Dim::Fixed(1), Dim::Fixed(1)),
+        Reduction::Colwise => (Dim::Fixed(1), cols),
+        Reduction::Rowwise => (rows, Dim::Fixed(1)),
+        Reduction::None => (rows, cols),
+    };
+    MLtType::new(elem, shape)
+}
+
+// rounding keeps integers, mod|rem of integers are integers, the parts of complex numbers are
+// real, anything else keeps the widest operand type and is at least real
+fn elementwise_elem(function_name: &str, elems: &[ElemType]) -> ElemType {
+    let widest = elems.iter().copied().max().unwrap_or(ElemType::Real);
+    match function_name {
+        "floor" | "ceil" | "round" if widest != ElemType::Complex => ElemType::Int,
+        // the magnitude, parts and phase of complex numbers are real
+        "abs" if widest == ElemType::Complex => ElemType::Real,
+        "real" | "imag" | "angle" => ElemType::Real,
+        "abs" | "sign" | "mod" | "rem" => widest.max(ElemType::Int),
+        _ => widest.max(ElemType::Real),
+    }
+}
+
+// `size(x, dim)`, `numel(x)` and `length(x)` from the shape of x - fixed sizes are folded into
+// literals, template parameters are written as they are and dynamic sizes are read at runtime
+pub fn size_dim(
+    function_name: &str,
+    function_params: &[MLtExpr],
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> Option<Dim> {
+    let (rows, cols) = expr_type(function_params.first()?, ti_state, line_num, quiet).shape();
+    match (function_name, &function_params[1..]) {
+        ("size", [MLtExpr::Basic(MLtLValue::Integer(dim))]) => match dim.as_str() {
+            "1" => Some(rows),
+            "2" => Some(cols),
+            _ => Some(Dim::Fixed(1)),
+        },
+        ("numel", []) => Some(rows * cols),
+        ("length", []) => match (rows, cols) {
+            (Dim::Fixed(0), _) | (_, Dim::Fixed(0)) => Some(Dim::Fixed(0)),
+            (Dim::Fixed(rows), Dim::Fixed(cols)) => Some(Dim::Fixed(rows.max(cols))),
+            (Dim::Fixed(1), dim) | (dim, Dim::Fixed(1)) => Some(dim),
+            (rows, cols) if rows == cols => Some(rows),
+            _ => Some(Dim::Dynamic),
+        },
+        _ => None,
+    }
+}
+
+// a size or count argument, like the counts given to repmat - literals, the sizes of other
+// matrices and sums and products of them are known, anything else is a runtime value
+pub fn dim_argument(
+    expr: &MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> Dim {
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(n)) => {
+            Dim::Fixed(n.parse().expect("failed to parse integer to int"))
+        }
+        MLtExpr::Basic(MLtLValue::FunctionCall(fname, params)) => {
+            size_dim(fname, params, ti_state, line_num, quiet).unwrap_or(Dim::Dynamic)
+        }
+        MLtExpr::Parenthesized(e) => dim_argument(e, ti_state, line_num, quiet),
+        MLtExpr::BinOp(left, op, right) => {
+            let left = dim_argument(left, ti_state, line_num, quiet);
+            let right = dim_argument(right, ti_state, line_num, quiet);
+            match (op, left, right) {
+                (MLtBinOp::Add, left, right) => left + right,
+                (MLtBinOp::Mul | MLtBinOp::CwiseMul, left, right) => left * right,
+                (MLtBinOp::Sub, Dim::Fixed(left), Dim::Fixed(right)) if left >= right => {
+                    Dim::Fixed(left - right)
+                }
+                (MLtBinOp::Div | MLtBinOp::CwiseDiv, Dim::Fixed(left), Dim::Fixed(right))
+                    if right != 0 && left % right == 0 =>
+                {
+                    Dim::Fixed(left / right)
+                }
+                _ => Dim::Dynamic,
+            }
         }
+        _ => Dim::Dynamic,
     }
 }
 
+// the (rows, cols) arguments of zeros, reshape, rand and the like - `f(n)` is n by n, and
+// `f([r c])` and `f(size(x))` are r by c
+pub fn shape_argument_exprs(args: &[MLtExpr]) -> Option<(MLtExpr, MLtExpr)> {
+    match args {
+        [MLtExpr::Basic(MLtLValue::FunctionCall(fname, params))]
+            if fname == "size" && params.len() == 1 =>
+        {
+            let size = |dim: &str| {
+                MLtExpr::Basic(MLtLValue::FunctionCall(
+                    "size".to_string(),
+                    vec![
+                        params[0].clone(),
+                        MLtExpr::Basic(MLtLValue::Integer(dim.to_string())),
+                    ],
+                ))
+            };
+            Some((size("1"), size("2")))
+        }
+        [MLtExpr::Basic(MLtLValue::InlineMatrix(rows))] => match rows.as_slice() {
+            [row] if row.len() == 2 => Some((row[0].clone(), row[1].clone())),
+            _ => None,
+        },
+        [n] => Some((n.clone(), n.clone())),
+        [rows, cols] => Some((rows.clone(), cols.clone())),
+        _ => None,
+    }
+}
+
+pub fn shape_arguments(
+    args: &[MLtExpr],
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> Option<(Dim, Dim)> {
+    let (rows, cols) = shape_argument_exprs(args)?;
+    Some((
+        dim_argument(&rows, ti_state, line_num, quiet),
+        dim_argument(&cols, ti_state, line_num, quiet),
+    ))
+}
+
 pub fn lvalue_type(
     lvalue: &MLtLValue,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
-) -> (u32, u32) {
+    quiet: bool,
+) -> MLtType {
     match lvalue {
-        MLtLValue::Integer(_) | MLtLValue::Float(_) => (1, 1),
-        MLtLValue::Matrix(matrix) => matrix_type("", matrix, ti_state),
+        MLtLValue::Integer(_) => MLtType::fixed(ElemType::Int, (1, 1)),
+        MLtLValue::Float(_) => MLtType::real((1, 1)),
+        MLtLValue::Imaginary(_) => MLtType::fixed(ElemType::Complex, (1, 1)),
+        MLtLValue::Matrix(matrix) => matrix_type("", matrix, ti_state, quiet),
         MLtLValue::StructMatrix(prefix, matrix) => {
-            matrix_type(format!("{}.", prefix).as_str(), matrix, ti_state)
+            matrix_type(format!("{}.", prefix).as_str(), matrix, ti_state, quiet)
         }
-        MLtLValue::InlineMatrix(lvalues) => inline_matrix_type(lvalues, ti_state, line_num),
+        MLtLValue::InlineMatrix(lvalues) => inline_matrix_type(lvalues, ti_state, line_num, quiet),
+        MLtLValue::Char(chars) => MLtType::fixed(ElemType::Int, (1, chars.len() as u32)),
         MLtLValue::FunctionCall(function_name, function_params) => match function_name.as_str() {
-            "eye" => {
-                if let Some(MLtExpr::Basic(MLtLValue::Integer(n))) = function_params.get(0) {
-                    let n = n.parse().expect("Argument to eye must be an int");
-                    return (n, n);
-                }
-                panic!("eye expects one integer argument");
-            }
-            "ones" | "zeros" => {
-                if let Some(MLtExpr::Basic(MLtLValue::Integer(rows))) = function_params.get(0) {
-                    if let Some(MLtExpr::Basic(MLtLValue::Integer(cols))) = function_params.get(1) {
-                        let rows = rows.parse().expect("Argument to ones|zeros must be an int");
-                        let cols = cols.parse().expect("Argument to ones|zeros must be an int");
-                        return (rows, cols);
-                    } else {
-                        let rows_cols =
-                            rows.parse().expect("Argument to ones|zeros must be an int");
-                        return (rows_cols, rows_cols);
-                    }
+            "eye" | "ones" | "zeros" => {
+                match shape_arguments(function_params, ti_state, line_num, quiet) {
+                    Some(shape) => MLtType::new(ElemType::Real, shape),
+                    None => panic!("{} expects one or two size arguments", function_name),
                 }
-                panic!("ones|zeros expects two integer arguments");
             }
+            // single argument min|max (or `min(x, [], dim)`) is a reduction
+            "min" | "max" if function_params.len() != 2 => {
+                let elem = match function_params.first() {
+                    Some(expr) => expr_type(expr, ti_state, line_num, quiet).elem,
+                    None => ElemType::Real,
+                };
+                reduction_type(function_params, 2, ti_state, line_num, quiet, elem)
+            }
+            // sums of bools count them
+            "sum" | "prod" => {
+                let elem = match function_params.first() {
+                    Some(expr) => expr_type(expr, ti_state, line_num, quiet)
+                        .elem
+                        .max(ElemType::Int),
+                    None => ElemType::Real,
+                };
+                reduction_type(function_params, 1, ti_state, line_num, quiet, elem)
+            }
+            "mean" => reduction_type(
+                function_params,
+                1,
+                ti_state,
+                line_num,
+                quiet,
+                ElemType::Real,
+            ),
+            "any" | "all" => reduction_type(
+                function_params,
+                1,
+                ti_state,
+                line_num,
+                quiet,
+                ElemType::Bool,
+            ),
             // same size as the left arg
-            "expm" | "min" | "max" | "cross" | "abs" | "exp" => {
-                if let Some(expr) = function_params.get(0) {
-                    let (rows, cols) = expr_type(expr, ti_state, line_num);
-                    return (rows, cols);
+            "expm" | "min" | "max" | "cross" => {
+                let types: Vec<MLtType> = function_params
+                    .iter()
+                    .map(|e| expr_type(e, ti_state, line_num, quiet))
+                    .collect();
+                if let Some(left_type) = types.first() {
+                    let elem = types.iter().map(|t| t.elem).max().unwrap_or(ElemType::Real);
+                    return match function_name.as_str() {
+                        "min" | "max" => left_type.with_elem(elem),
+                        _ => left_type.with_elem(elem.max(ElemType::Real)),
+                    };
+                }
+                panic!("expm|min|max|cross expects at least one matrix argument");
+            }
+            fname if builtins::unary_elementwise(fname).is_some() => {
+                if let [expr] = function_params.as_slice() {
+                    let arg_type = expr_type(expr, ti_state, line_num, quiet);
+                    return arg_type.with_elem(elementwise_elem(fname, &[arg_type.elem]));
+                }
+                panic!("{} expects one argument", fname);
+            }
+            // scalars are broadcast against the other argument
+            fname if builtins::binary_elementwise(fname).is_some() => {
+                if let [left, right] = function_params.as_slice() {
+                    let left_type = expr_type(left, ti_state, line_num, quiet);
+                    let right_type = expr_type(right, ti_state, line_num, quiet);
+                    let elem = elementwise_elem(fname, &[left_type.elem, right_type.elem]);
+                    if left_type.is_scalar() {
+                        return right_type.with_elem(elem);
+                    }
+                    if !quiet && !right_type.is_scalar() && left_type.conflicts(&right_type) {
+                        println!(
+                            "Elementwise {} type warning: {} by {} with {} by {} on line {}.",
+                            fname,
+                            left_type.rows,
+                            left_type.cols,
+                            right_type.rows,
+                            right_type.cols,
+                            line_num
+                        );
+                    }
+                    return left_type.with_elem(elem);
+                }
+                panic!("{} expects two arguments", fname);
+            }
+            "size" if function_params.len() == 1 => {
+                expr_type(&function_params[0], ti_state, line_num, quiet);
+                MLtType::real((1, 2))
+            }
+            "size" | "numel" | "length" => {
+                if size_dim(function_name, function_params, ti_state, line_num, quiet).is_none() {
+                    panic!("{} expects a matrix argument", function_name);
+                }
+                MLtType::fixed(ElemType::Int, (1, 1))
+            }
+            "repmat" => {
+                if let Some((arg, counts)) = function_params.split_first()
+                    && let Some((rows, cols)) = shape_arguments(counts, ti_state, line_num, quiet)
+                {
+                    let t = expr_type(arg, ti_state, line_num, quiet);
+                    return t.with_shape((t.rows.clone() * rows, t.cols.clone() * cols));
                 }
-                panic!("expm|min|max|cross|abs|exp expects at least one matrix argument");
+                panic!("repmat expects a matrix and the number of copies");
+            }
+            // 100 points unless the count is given
+            "linspace" => match function_params.as_slice() {
+                [_, _] => MLtType::real((1, 100)),
+                [_, _, n] => MLtType::new(
+                    ElemType::Real,
+                    (Dim::Fixed(1), dim_argument(n, ti_state, line_num, quiet)),
+                ),
+                _ => panic!("linspace expects two or three arguments"),
+            },
+            "reshape" => {
+                if let Some((arg, dims)) = function_params.split_first()
+                    && let Some((rows, cols)) = shape_arguments(dims, ti_state, line_num, quiet)
+                {
+                    let t = expr_type(arg, ti_state, line_num, quiet);
+                    let (size, new_size) =
+                        (t.rows.clone() * t.cols.clone(), rows.clone() * cols.clone());
+                    if !quiet && size.conflicts(&new_size) {
+                        println!(
+                            "reshape type warning: {} by {} can't be reshaped to {} by {} on line {}.",
+                            t.rows, t.cols, rows, cols, line_num
+                        );
+                    }
+                    return t.with_shape((rows, cols));
+                }
+                panic!("reshape expects a matrix and the new size");
+            }
+            // the blocks go down the diagonal
+            "blkdiag" => {
+                let types: Vec<MLtType> = function_params
+                    .iter()
+                    .map(|e| expr_type(e, ti_state, line_num, quiet))
+                    .collect();
+                let elem = types.iter().map(|t| t.elem).max().unwrap_or(ElemType::Real);
+                let (rows, cols) = types
+                    .iter()
+                    .fold((Dim::Fixed(0), Dim::Fixed(0)), |(rows, cols), t| {
+                        (rows + t.rows.clone(), cols + t.cols.clone())
+                    });
+                MLtType::new(elem.max(ElemType::Real), (rows, cols))
+            }
+            "rand" | "randn" => match shape_arguments(function_params, ti_state, line_num, quiet) {
+                Some(shape) => MLtType::new(ElemType::Real, shape),
+                None if function_params.is_empty() => MLtType::real((1, 1)),
+                None => panic!("{} expects the size of the matrix", function_name),
+            },
+            "isempty" => MLtType::fixed(ElemType::Bool, (1, 1)),
+            "norm" | "trace" | "det" => MLtType::real((1, 1)),
+            "inv" | "chol" => {
+                if let Some(expr) = function_params.first() {
+                    let (rows, cols) = expr_type(expr, ti_state, line_num, quiet).shape();
+                    if !quiet && rows.conflicts(&cols) {
+                        println!(
+                            "{} type warning: {} by {} is not square on line {}.",
+                            function_name, rows, cols, line_num
+                        );
+                    }
+                    return MLtType::new(ElemType::Real, (rows, cols));
+                }
+                panic!("{} expects one matrix argument", function_name);
+            }
+            "pinv" => {
+                if let Some(expr) = function_params.first() {
+                    let (rows, cols) = expr_type(expr, ti_state, line_num, quiet).shape();
+                    return MLtType::new(ElemType::Real, (cols, rows));
+                }
+                panic!("pinv expects one matrix argument");
+            }
+            // R, the same shape as the argument
+            "qr" => {
+                if let Some(expr) = function_params.first() {
+                    return expr_type(expr, ti_state, line_num, quiet);
+                }
+                panic!("qr expects one matrix argument");
+            }
+            // single output forms return the eigenvalues / singular values as a vector
+            "eig" | "selfadjoint_eig" => {
+                if let Some(expr) = function_params.first() {
+                    let (rows, _) = expr_type(expr, ti_state, line_num, quiet).shape();
+                    return MLtType::new(ElemType::Real, (rows, Dim::Fixed(1)));
+                }
+                panic!("eig expects one matrix argument");
+            }
+            "svd" => {
+                if let Some(expr) = function_params.first() {
+                    let (rows, cols) = expr_type(expr, ti_state, line_num, quiet).shape();
+                    return MLtType::new(ElemType::Real, (rows.min(cols), Dim::Fixed(1)));
+                }
+                panic!("svd expects one matrix argument");
+            }
+            "kron" => {
+                if let [left, right] = function_params.as_slice() {
+                    let (lrows, lcols) = expr_type(left, ti_state, line_num, quiet).shape();
+                    let (rrows, rcols) = expr_type(right, ti_state, line_num, quiet).shape();
+                    return MLtType::new(ElemType::Real, (lrows * rrows, lcols * rcols));
+                }
+                panic!("kron expects two matrix arguments");
             }
-            "norm" => (1, 1),
             "diag" => {
-                if let Some(expr) = function_params.get(0) {
-                    let (rows, cols) = expr_type(expr, ti_state, line_num);
+                if let Some(expr) = function_params.first() {
+                    let (rows, cols) = expr_type(expr, ti_state, line_num, quiet).shape();
                     if cols == 1 {
-                        return (rows, rows);
+                        return MLtType::new(ElemType::Real, (rows.clone(), rows));
+                    }
+                    if rows == 1 {
+                        return MLtType::new(ElemType::Real, (cols.clone(), cols));
                     }
                 }
                 panic!("diag expects one vector argument");
             }
             fname => {
-                if let Some((rows, cols)) = ti_state.get(fname) {
-                    (*rows, *cols)
+                if let Some(t) = ti_state.get(fname).cloned() {
+                    if let Some(params) = &t.params {
+                        check_call(fname, params, function_params, ti_state, line_num, quiet);
+                    }
+                    // the result is a value, not the function
+                    t.with_shape(t.shape())
                 } else {
-                    println!("Couldn't find {} in functions", fname);
-                    (0, 0)
+                    if !quiet {
+                        println!("Couldn't find {} in functions", fname);
+                    }
+                    MLtType::dynamic()
                 }
             }
         },
     }
 }
 
+// quaternions are 1 by 4 rows in matlab, these are only known with the `quaternions` option
+pub fn register_quaternion_types(ti_state: &mut HashMap<String, MLtType>) {
+    for (name, shape) in [
+        ("quatmultiply", (1, 4)),
+        ("quatconj", (1, 4)),
+        ("quatinv", (1, 4)),
+        ("quatnormalize", (1, 4)),
+        ("quat2rotm", (3, 3)),
+        ("rotm2quat", (1, 4)),
+    ] {
+        ti_state.insert(name.to_string(), MLtType::real(shape));
+    }
+}
+
+// types of each output for `[a, b] = f(x)`
+pub fn multi_output_types(
+    function_name: &str,
+    function_params: &[MLtExpr],
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> Vec<MLtType> {
+    let (rows, cols) = match function_params.first() {
+        Some(expr) => expr_type(expr, ti_state, line_num, quiet).shape(),
+        None => panic!("{} expects one matrix argument", function_name),
+    };
+    let shapes = match function_name {
+        "eig" | "selfadjoint_eig" => vec![(&rows, &rows), (&rows, &rows)], // [V, D]
+        "qr" => vec![(&rows, &rows), (&rows, &cols)],                      // [Q, R]
+        "svd" => vec![(&rows, &rows), (&rows, &cols), (&cols, &cols)],     // [U, S, V]
+        fname => panic!("{} does not support multiple outputs", fname),
+    };
+    shapes
+        .into_iter()
+        .map(|(rows, cols)| MLtType::new(ElemType::Real, (rows.clone(), cols.clone())))
+        .collect()
+}
+
+fn check_call(
+    function_name: &str,
+    params: &[MLtType],
+    args: &[MLtExpr],
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) {
+    if params.len() != args.len() {
+        if !quiet {
+            println!(
+                "Function call type warning: {} expects {} arguments, but is called with {} on line {}.",
+                function_name,
+                params.len(),
+                args.len(),
+                line_num
+            );
+        }
+        return;
+    }
+    for (idx, (param, arg)) in params.iter().zip(args).enumerate() {
+        let arg_type = expr_type(arg, ti_state, line_num, quiet);
+        if !quiet && param.conflicts(&arg_type) {
+            println!(
+                "Function call type warning: argument {} of {} should be {} by {}, but is {} by {} on line {}.",
+                idx + 1,
+                function_name,
+                param.rows,
+                param.cols,
+                arg_type.rows,
+                arg_type.cols,
+                line_num
+            );
+        }
+    }
+}
+
+// the variable a plain assignment declares, when it isn't known yet
+pub fn declared_name(lvalue: &MLtLValue) -> Option<String> {
+    match lvalue {
+        MLtLValue::Matrix(MLtMatrixAccess::Matrix(name)) => Some(name.clone()),
+        MLtLValue::StructMatrix(prefix, MLtMatrixAccess::Matrix(name)) => {
+            Some(format!("{}.{}", prefix, name))
+        }
+        _ => None,
+    }
+}
+
+// the type a variable is declared with when this is the first assignment to it - literals assigned
+// once are substituted by constant propagation, so a variable initialized to an integer literal is
+// reassigned and may not stay an integer
+pub fn assignment_type(
+    expr: &MLtExpr,
+    ti_state: &mut HashMap<String, MLtType>,
+    line_num: &mut u32,
+    quiet: bool,
+) -> MLtType {
+    let t = expr_type(expr, ti_state, line_num, quiet);
+    match expr {
+        MLtExpr::Basic(MLtLValue::Integer(_)) => t.with_elem(ElemType::Real),
+        MLtExpr::Negation(e) | MLtExpr::Parenthesized(e)
+            if matches!(**e, MLtExpr::Basic(MLtLValue::Integer(_))) =>
+        {
+            t.with_elem(ElemType::Real)
+        }
+        _ => t,
+    }
+}
+
+// `%#type P 12x12 double` - sizes are read with `Dim::parse`, and the element type uses matlab's
+// class names, defaulting to real
+pub fn annotation_type((rows, cols): &(String, String), elem: &Option<String>) -> MLtType {
+    let elem = match elem.as_deref() {
+        Some("logical" | "bool") => ElemType::Bool,
+        Some(
+            "int" | "int8" | "int16" | "int32" | "int64" | "uint8" | "uint16" | "uint32" | "uint64",
+        ) => ElemType::Int,
+        Some("complex") => ElemType::Complex,
+        _ => ElemType::Real,
+    };
+    MLtType::new(elem, (Dim::parse(rows), Dim::parse(cols)))
+}
+
+fn is_param(function: &MLtFunction, name: &str) -> bool {
+    function
+        .params
+        .iter()
+        .any(|p| p.strip_prefix("&").unwrap_or(p) == name)
+}
+
+// annotations of the parameters, which are needed before the body is converted
+pub fn signature_annotations(function: &MLtFunction) -> Vec<(String, MLtType)> {
+    function
+        .body
+        .iter()
+        .filter_map(|statement| match statement {
+            MLtStatement::TypeAnnotation(name, dims, elem) if is_param(function, name) => {
+                Some((name.clone(), annotation_type(dims, elem)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// the type of the variable a function returns - a parameter, or its declaration in the body
+pub fn return_type(function: &MLtFunction, ti_state: &HashMap<String, MLtType>) -> Option<MLtType> {
+    let name = &function.return_obj;
+    if is_param(function, name) {
+        return ti_state.get(name).cloned();
+    }
+    declared_types(&function.body, ti_state, 0, true)
+        .remove(name)
+        .or_else(|| ti_state.get(name).cloned())
+}
+
+// checks a type given in the matlab file against the one known or assigned for the variable - a
+// wider element type is fine, since the value fits
+pub fn check_annotation(name: &str, annotated: &MLtType, known: &MLtType, line_num: u32) {
+    if annotated.conflicts(known) || annotated.elem < known.elem {
+        println!(
+            "Annotation type warning: {} is annotated as {:?} {} by {}, but is {:?} {} by {} on line {}.",
+            name,
+            annotated.elem,
+            annotated.rows,
+            annotated.cols,
+            known.elem,
+            known.rows,
+            known.cols,
+            line_num
+        );
+    }
+}
+
+// a declaration pass over a function body or a branch
+struct Declarations<'a> {
+    types: HashMap<String, MLtType>,
+    // the parameters and the variables declared before, which keep their types
+    known: &'a HashMap<String, MLtType>,
+    // the assigned variables in the order they are first assigned
+    order: Vec<String>,
+    // assignments that change the shape of a variable: the name, the declared type, and the type
+    // assigned with its line
+    conflicts: Vec<(String, MLtType, MLtType, u32)>,
+    // variables annotated before they are assigned, which are declared with the annotated type
+    annotated: HashMap<String, MLtType>,
+    // the first assignment to each annotated variable, to check the annotation against
+    first_assigned: Vec<(String, MLtType, u32)>,
+    line_num: u32,
+}
+
+impl Declarations<'_> {
+    // declares `name`, or widens it if it isn't `known` and `t` is wider with the same shape or
+    // replaces a dynamic size - returns whether the type changed
+    fn declare(&mut self, name: String, t: MLtType) -> bool {
+        let widens = match self.types.get(&name) {
+            None => true,
+            Some(_) if self.known.contains_key(&name) || self.annotated.contains_key(&name) => {
+                false
+            }
+            Some(declared) if declared.conflicts(&t) => {
+                self.conflicts
+                    .push((name.clone(), declared.clone(), t.clone(), self.line_num));
+                false
+            }
+            Some(declared) if declared.is_dynamic() && !t.is_dynamic() => true,
+            Some(declared) => declared.shape() == t.shape() && declared.elem < t.elem,
+        };
+        if widens {
+            let elem = self.types.get(&name).map_or(t.elem, |d| d.elem.max(t.elem));
+            self.types.insert(name, t.with_elem(elem));
+        }
+        widens
+    }
+
+    // a plain assignment, which is what declares a variable in the output
+    fn assign(&mut self, name: String, t: MLtType) -> bool {
+        if !self.types.contains_key(&name) {
+            self.order.push(name.clone());
+            if let Some(annotated) = self.annotated.get(&name) {
+                self.first_assigned.push((name.clone(), t, self.line_num));
+                self.types.insert(name, annotated.clone());
+                return true;
+            }
+        }
+        self.declare(name, t)
+    }
+}
+
+// declares the variables assigned in the statements that aren't `known`, and widens the element
+// type of the ones that are assigned a wider value of the same shape later on - branches share the
+// state, since a variable assigned in a branch has to be wide enough for both
+// returns whether anything was declared or widened
+fn declare_statements(statements: &[MLtStatement], declarations: &mut Declarations) -> bool {
+    let mut changed = false;
+    for statement in statements {
+        let line_num = &mut declarations.line_num;
+        match statement {
+            MLtStatement::Assignment(lvalue, expr) => {
+                if let Some(name) = declared_name(lvalue) {
+                    // an annotated variable doesn't need room for reassignments of a literal
+                    let t = match declarations.annotated.contains_key(&name) {
+                        true => expr_type(expr, &mut declarations.types, line_num, true),
+                        false => assignment_type(expr, &mut declarations.types, line_num, true),
+                    };
+                    changed |= declarations.assign(name, t);
+                }
+            }
+            MLtStatement::MultiAssignment(lvalues, expr) => {
+                if let MLtExpr::Basic(MLtLValue::FunctionCall(name, params)) = expr
+                    && ["eig", "selfadjoint_eig", "qr", "svd"].contains(&name.as_str())
+                {
+                    let types =
+                        multi_output_types(name, params, &mut declarations.types, line_num, true);
+                    for (lvalue, t) in lvalues.iter().zip(types) {
+                        if let Some(name) = declared_name(lvalue) {
+                            changed |= declarations.assign(name, t);
+                        }
+                    }
+                }
+            }
+            // struct members are declared with their struct, other variables by their first
+            // assignment
+            MLtStatement::TypeAnnotation(name, dims, elem) if name.contains('.') => {
+                changed |= declarations.declare(name.clone(), annotation_type(dims, elem));
+            }
+            MLtStatement::TypeAnnotation(name, dims, elem)
+                if !declarations.types.contains_key(name) =>
+            {
+                let t = annotation_type(dims, elem);
+                declarations.annotated.insert(name.clone(), t);
+            }
+            // hoisted subexpressions are only used in their own block
+            MLtStatement::Constant(name, expr) | MLtStatement::Temporary(name, expr) => {
+                let t = expr_type(expr, &mut declarations.types, line_num, true);
+                changed |= declarations.declare(name.clone(), t);
+            }
+            MLtStatement::IfStatement(_, body) | MLtStatement::PersistentInit(_, body) => {
+                *line_num += 1;
+                changed |= declare_statements(body, declarations);
+            }
+            MLtStatement::NewLine | MLtStatement::Persistent(_) => *line_num += 1,
+            _ => {}
+        }
+    }
+    changed
+}
+
+// runs the declaration passes from `line_num` until nothing changes, since widening a variable can
+// widen the ones computed from it
+fn declare_until_unchanged<'a>(
+    statements: &[MLtStatement],
+    ti_state: &'a HashMap<String, MLtType>,
+    line_num: u32,
+) -> Declarations<'a> {
+    let mut declarations = Declarations {
+        types: ti_state.clone(),
+        known: ti_state,
+        order: vec![],
+        conflicts: vec![],
+        annotated: HashMap::new(),
+        first_assigned: vec![],
+        line_num,
+    };
+    loop {
+        declarations.conflicts.clear();
+        declarations.line_num = line_num;
+        if !declare_statements(statements, &mut declarations) {
+            return declarations;
+        }
+    }
+}
+
+// the types the variables of a function body are declared with, widened over every assignment to
+// them - `n = sum(x > 0); n = n / 2` declares a real `n`, not an int
+// annotated variables are declared with their annotation, which is checked against their first
+// assignment from the function's `line_num`
+pub fn declared_types(
+    statements: &[MLtStatement],
+    ti_state: &HashMap<String, MLtType>,
+    line_num: u32,
+    quiet: bool,
+) -> HashMap<String, MLtType> {
+    let declarations = declare_until_unchanged(statements, ti_state, line_num);
+    if !quiet {
+        for (name, t, line) in &declarations.first_assigned {
+            check_annotation(name, &declarations.annotated[name], t, *line);
+        }
+    }
+    let mut declared = declarations.types;
+    declared.retain(|name, _| !ti_state.contains_key(name));
+    declared
+}
+
+// variables first assigned inside an if have to be declared before it, so they are still in scope
+// after it and on the path where the if doesn't run - returns them in the order they are first
+// assigned, with the types they are `declared` with in the function
+pub fn branch_declarations(
+    statements: &[MLtStatement],
+    ti_state: &HashMap<String, MLtType>,
+    declared: &HashMap<String, MLtType>,
+    line_num: u32,
+    quiet: bool,
+) -> Vec<(String, MLtType)> {
+    // the branch starts on the line after the if
+    let declarations = declare_until_unchanged(statements, ti_state, line_num + 1);
+    let hoisted: Vec<(String, MLtType)> = declarations
+        .order
+        .iter()
+        .map(|name| {
+            let t = declared.get(name).unwrap_or(&declarations.types[name]);
+            (name.clone(), t.clone())
+        })
+        .collect();
+    // the lines are counted from the if, so they move down by the declarations written before it
+    let offset = hoisted.len() as u32;
+    for (name, first_type, t, line) in declarations.conflicts {
+        if !quiet {
+            println!(
+                "Branch type warning: {} is declared as {} by {}, but assigned as {} by {} on line {}.",
+                name,
+                first_type.rows,
+                first_type.cols,
+                t.rows,
+                t.cols,
+                line + offset
+            );
+        }
+    }
+    hoisted
+}
+
+// comparisons are elementwise, with scalars broadcast against the other side
+fn comparison_type(
+    left_type: MLtType,
+    right_type: MLtType,
+    line_num: &mut u32,
+    quiet: bool,
+) -> MLtType {
+    let shape = if left_type.is_scalar() {
+        right_type.shape()
+    } else {
+        if !quiet && !right_type.is_scalar() && left_type.conflicts(&right_type) {
+            println!(
+                "Comparison type warning: {} by {} with {} by {} on line {}.",
+                left_type.rows, left_type.cols, right_type.rows, right_type.cols, line_num
+            );
+        }
+        left_type.shape()
+    };
+    MLtType::new(ElemType::Bool, shape)
+}
+
 pub fn expr_type(
     expr: &MLtExpr,
-    ti_state: &mut HashMap<String, (u32, u32)>,
+    ti_state: &mut HashMap<String, MLtType>,
     line_num: &mut u32,
-) -> (u32, u32) {
+    quiet: bool,
+) -> MLtType {
     match expr {
-        MLtExpr::Basic(mlt_lvalue) => lvalue_type(mlt_lvalue, ti_state, line_num),
-        MLtExpr::Negation(mlt_expr) => expr_type(mlt_expr, ti_state, line_num),
-        MLtExpr::Transposed(mlt_expr) => {
-            let (cols, rows) = expr_type(mlt_expr, ti_state, line_num);
-            (rows, cols) // transpose reverses the order
+        MLtExpr::Basic(mlt_lvalue) => lvalue_type(mlt_lvalue, ti_state, line_num, quiet),
+        // negating a bool gives a number
+        MLtExpr::Negation(mlt_expr) => {
+            let t = expr_type(mlt_expr, ti_state, line_num, quiet);
+            t.with_elem(t.elem.max(ElemType::Int))
+        }
+        MLtExpr::Transposed(mlt_expr) | MLtExpr::PlainTransposed(mlt_expr) => {
+            let t = expr_type(mlt_expr, ti_state, line_num, quiet);
+            t.with_shape((t.cols.clone(), t.rows.clone())) // transpose reverses the order
+        }
+        MLtExpr::Parenthesized(mlt_expr) => expr_type(mlt_expr, ti_state, line_num, quiet),
+        MLtExpr::Normalized(mlt_expr) => {
+            let t = expr_type(mlt_expr, ti_state, line_num, quiet);
+            t.with_elem(t.elem.max(ElemType::Real))
+        }
+        MLtExpr::SquaredNorm(mlt_expr) => {
+            expr_type(mlt_expr, ti_state, line_num, quiet);
+            MLtType::real((1, 1))
+        }
+        MLtExpr::Skew(mlt_expr) => {
+            let (rows, cols) = expr_type(mlt_expr, ti_state, line_num, quiet).shape();
+            if !quiet && (rows.conflicts(&Dim::Fixed(3)) || cols.conflicts(&Dim::Fixed(1))) {
+                println!(
+                    "Skew type warning: {} by {} is not a 3 vector on line {}.",
+                    rows, cols, line_num
+                );
+            }
+            MLtType::real((3, 3))
         }
-        MLtExpr::Parenthesized(mlt_expr) => expr_type(mlt_expr, ti_state, line_num),
         MLtExpr::BinOp(left, mlt_bin_op, right) => {
+            let left_type = expr_type(left, ti_state, line_num, quiet);
+            let right_type = expr_type(right, ti_state, line_num, quiet);
+            let (lrows, lcols) = left_type.shape();
+            let (rrows, rcols) = right_type.shape();
+            // arithmetic on bools gives numbers, division and powers of integers give reals
+            let elem = left_type.elem.max(right_type.elem);
+            let arithmetic_elem = elem.max(ElemType::Int);
+            let division_elem = elem.max(ElemType::Real);
             match mlt_bin_op {
                 MLtBinOp::Add | MLtBinOp::Sub => {
-                    let (lrows, lcols) = expr_type(left, ti_state, line_num);
-                    let (rrows, rcols) = expr_type(right, ti_state, line_num);
-                    if lrows != rrows || lcols != rcols {
+                    if !quiet && left_type.conflicts(&right_type) {
                         println!(
                             "Matrix add/sub type warning: {} by {} +/- {} by {} on line {}.",
                             lrows, lcols, rrows, rcols, line_num
                         );
                     }
-                    (lrows, lcols)
+                    MLtType::new(arithmetic_elem, (lrows, lcols))
                 }
                 MLtBinOp::Mul => {
-                    let (lrows, lcols) = expr_type(left, ti_state, line_num);
-                    let (rrows, rcols) = expr_type(right, ti_state, line_num);
-                    if lrows == 1 && lcols == 1 {
+                    let shape = if left_type.is_scalar() {
                         // mul by scalar
                         (rrows, rcols)
-                    } else if rrows == 1 && rcols == 1 {
+                    } else if right_type.is_scalar() {
                         // mul by scalar
                         (lrows, lcols)
                     } else {
-                        if lcols != rrows {
+                        if !quiet && lcols.conflicts(&rrows) {
                             println!(
                                 "Matrix mul type warning: {} by {} * {} by {} on line {}.",
                                 lrows, lcols, rrows, rcols, line_num
                             );
                         }
                         (lrows, rcols)
-                    }
+                    };
+                    MLtType::new(arithmetic_elem, shape)
                 }
                 MLtBinOp::Div => {
-                    let (lrows, lcols) = expr_type(left, ti_state, line_num);
-                    let (rrows, rcols) = expr_type(right, ti_state, line_num);
-                    if rrows == 1 && rcols == 1 {
+                    let shape = if right_type.is_scalar() {
                         // division by scalar
                         (lrows, lcols)
                     } else {
                         // same as multiplying by the inverse, which doesn't change the size
-                        if lcols != rrows {
+                        if !quiet && lcols.conflicts(&rrows) {
                             println!(
                                 "Matrix div type warning: {} by {} / {} by {} on line {}.",
                                 lrows, lcols, rrows, rcols, line_num
                             );
                         }
                         (lrows, rcols)
+                    };
+                    MLtType::new(division_elem, shape)
+                }
+                MLtBinOp::Pow => {
+                    if !quiet && !right_type.is_scalar() {
+                        println!(
+                            "Matrix pow type error: exponent must be a scalar, got {} by {} on line {}.",
+                            rrows, rcols, line_num
+                        );
+                    } else if !quiet && lrows.conflicts(&lcols) {
+                        println!(
+                            "Matrix pow type error: {} by {} is not square on line {}.",
+                            lrows, lcols, line_num
+                        );
                     }
+                    MLtType::new(division_elem, (lrows, lcols))
+                }
+                MLtBinOp::CwisePow => {
+                    let shape = if left_type.is_scalar() {
+                        // scalar base is broadcast against the exponents
+                        (rrows, rcols)
+                    } else {
+                        if !quiet && !right_type.is_scalar() && left_type.conflicts(&right_type) {
+                            println!(
+                                "Matrix cwise pow type warning: {} by {} .^ {} by {} on line {}.",
+                                lrows, lcols, rrows, rcols, line_num
+                            );
+                        }
+                        (lrows, lcols)
+                    };
+                    MLtType::new(division_elem, shape)
                 }
-                MLtBinOp::Pow | MLtBinOp::CwisePow => expr_type(left, ti_state, line_num),
-                MLtBinOp::CwiseMul | MLtBinOp::CwiseDiv => expr_type(left, ti_state, line_num),
-                MLtBinOp::And | MLtBinOp::Or => (1, 1), // float is basically a bool - TODO - check that inputs are bools
-                MLtBinOp::EqualTo | MLtBinOp::NotEqualTo => (1, 1), // float is basically a bool - TODO - check that input shapes match
-                MLtBinOp::LessThan
+                MLtBinOp::CwiseMul => left_type.with_elem(arithmetic_elem),
+                MLtBinOp::CwiseDiv => left_type.with_elem(division_elem),
+                // && and || short circuit, so matlab only allows them on scalars
+                MLtBinOp::And | MLtBinOp::Or => {
+                    for t in [&left_type, &right_type] {
+                        if !quiet && !t.is_scalar() {
+                            println!(
+                                "Logical op type warning: && and || need scalars, got {} by {} on line {}.",
+                                t.rows, t.cols, line_num
+                            );
+                        }
+                    }
+                    MLtType::fixed(ElemType::Bool, (1, 1))
+                }
+                MLtBinOp::EqualTo
+                | MLtBinOp::NotEqualTo
+                | MLtBinOp::LessThan
                 | MLtBinOp::LessThanEqualTo
                 | MLtBinOp::GreaterThan
-                | MLtBinOp::GreaterThanEqualTo => (1, 1),
+                | MLtBinOp::GreaterThanEqualTo => {
+                    comparison_type(left_type, right_type, line_num, quiet)
+                }
+            }
+        }
+        MLtExpr::SymmetricProduct(left, middle) => {
+            let left_type = expr_type(left, ti_state, line_num, quiet);
+            let middle_type = expr_type(middle, ti_state, line_num, quiet);
+            let elem = left_type.elem.max(middle_type.elem).max(ElemType::Real);
+            let (lrows, lcols) = left_type.shape();
+            let (mrows, mcols) = middle_type.shape();
+            if !quiet && (lcols.conflicts(&mrows) || mrows.conflicts(&mcols)) {
+                println!(
+                    "Matrix mul type warning: {} by {} * {} by {} * {} by {} on line {}.",
+                    lrows, lcols, mrows, mcols, lcols, lrows, line_num
+                );
             }
+            MLtType::new(elem, (lrows.clone(), lrows))
         }
     }
 }